@@ -1,9 +1,15 @@
-use std::{io::Write, path::PathBuf};
+use std::path::PathBuf;
 
 use askama::Template;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::{
+    api::ApiConfig, certs::CertConfig, checks::CheckConfig, events::AlertConfig,
+    health::HealthConfig, logging::LogConfig, security::SecurityConfig, transmit::TransportConfig,
+    update::UpdateConfig,
+};
+
 pub struct Agent;
 
 #[derive(Error, Debug)]
@@ -22,6 +28,8 @@ pub enum Error {
     Encoding(#[from] toml::ser::Error),
     #[error("{0}")]
     Decoding(#[from] toml::de::Error),
+    #[error("Root access needed to manage the system service")]
+    Root,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,17 +37,87 @@ pub struct AgentConfig {
     pub agent: String,
     #[serde(default = "agent_default")]
     pub agent_only: bool,
+    /// the API reports are sent to
+    #[serde(default = "api_origin_default")]
+    pub api_origin: String,
+    /// seconds between scans
+    #[serde(default = "scan_interval_secs_default")]
+    pub scan_interval_secs: u64,
+    /// ranges to scan instead of the LAN interface's subnet, each on a directly attached
+    /// interface, rogue dhcp detection probes every one of those interfaces
+    #[serde(default)]
+    pub subnets: Vec<ipnetwork::IpNetwork>,
+    /// how long a scan in progress gets to wrap up and spool its report on SIGTERM,
+    /// keep it under the container runtime's grace period
+    #[serde(default = "shutdown_deadline_secs_default")]
+    pub shutdown_deadline_secs: u64,
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub checks: Vec<CheckConfig>,
+    #[serde(default)]
+    pub certificates: CertConfig,
+    #[serde(default)]
+    pub logging: LogConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub update: UpdateConfig,
+    #[serde(default)]
+    pub transport: TransportConfig,
 }
 
 fn agent_default() -> bool {
     false
 }
 
+fn api_origin_default() -> String {
+    "https://api.reverseping.net".to_string()
+}
+
+fn scan_interval_secs_default() -> u64 {
+    60
+}
+
+fn shutdown_deadline_secs_default() -> u64 {
+    8
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            agent: String::new(),
+            agent_only: agent_default(),
+            api_origin: api_origin_default(),
+            scan_interval_secs: scan_interval_secs_default(),
+            subnets: Vec::new(),
+            shutdown_deadline_secs: shutdown_deadline_secs_default(),
+            alerts: AlertConfig::default(),
+            security: SecurityConfig::default(),
+            health: HealthConfig::default(),
+            checks: Vec::new(),
+            certificates: CertConfig::default(),
+            logging: LogConfig::default(),
+            api: ApiConfig::default(),
+            update: UpdateConfig::default(),
+            transport: TransportConfig::default(),
+        }
+    }
+}
+
 impl Agent {
     const BIN_NAME: &'static str = env!("CARGO_BIN_NAME");
     const NAME: &'static str = env!("CARGO_PKG_NAME");
     const CONFIG_FILE: &'static str = "config.toml";
     const LOG_FILE: &'static str = "debug.log";
+    /// systemd restarts the daemon when the scan loop stops making progress for this long
+    pub const WATCHDOG: std::time::Duration = std::time::Duration::from_secs(900);
+    /// a scan and its report are abandoned after this, short of the watchdog
+    pub const SCAN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(840);
 
     fn config_dir() -> Result<PathBuf, Error> {
         let path = directories::BaseDirs::new()
@@ -50,62 +128,122 @@ impl Agent {
         Ok(path)
     }
 
-    fn config_file() -> Result<PathBuf, Error> {
+    pub fn config_file() -> Result<PathBuf, Error> {
         let path = Self::config_dir()?.join(Self::CONFIG_FILE);
         Ok(path)
     }
 
+    /// path for a file the agent keeps state in between runs
+    pub fn data_file(name: &str) -> Result<PathBuf, Error> {
+        Ok(Self::config_dir()?.join(name))
+    }
+
+    /// name of the systemd unit the daemon is installed as
+    #[cfg(target_os = "linux")]
+    pub fn service_name() -> String {
+        format!("{}.service", Self::NAME)
+    }
+
+    /// the oneshot unit and timer that run `update --auto`
+    #[cfg(target_os = "linux")]
+    fn update_unit_names() -> (String, String) {
+        (
+            format!("{}-update.service", Self::NAME),
+            format!("{}-update.timer", Self::NAME),
+        )
+    }
+
     pub fn remove_config() -> Result<(), Error> {
         std::fs::remove_dir_all(Self::config_dir()?)?;
         Ok(())
     }
 
-    pub fn write_log<S: Into<String>>(data: S) -> Result<(), Error> {
-        let data: String = data.into();
-        eprintln!("{}", &data);
-        let path = Self::config_dir()?.join(Self::LOG_FILE);
-        let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
-        file.write_all(data.as_bytes())?;
-        Ok(())
+    pub fn log_file() -> Result<PathBuf, Error> {
+        Ok(Self::config_dir()?.join(Self::LOG_FILE))
     }
 
-    pub fn save_agent_config(agent_id: &str, agent_only: bool) -> Result<AgentConfig, Error> {
-        let conf = AgentConfig {
-            agent: agent_id.to_string(),
-            agent_only,
-        };
-        let agent_file_contents = toml::to_string(&conf)?;
-        std::fs::write(Self::config_file()?, agent_file_contents)?;
-        Ok(conf)
+    pub fn save_agent_config(agent_id: &str, agent_only: bool) -> Result<(), Error> {
+        Self::set_config_values(vec![
+            ("agent", agent_id.into()),
+            ("agent_only", agent_only.into()),
+        ])
     }
 
-    pub fn get_agent_config() -> Result<AgentConfig, Error> {
+    /// Set top level keys in config.toml, anything else in the file stays as
+    /// written so defaults don't shadow the system config
+    pub fn set_config_values(values: Vec<(&str, toml::Value)>) -> Result<(), Error> {
         let path = Self::config_file()?;
-        if !path.exists() {
-            return Err(Error::AgentNotConfigured);
+        let mut conf = if path.exists() {
+            toml::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            toml::value::Table::new()
+        };
+        for (key, value) in values {
+            conf.insert(key.to_string(), value);
         }
-        let config = std::fs::read_to_string(path)?;
-        let agent_file: AgentConfig = toml::from_str(&config)?;
-        Ok(agent_file)
+        std::fs::write(path, toml::to_string(&conf)?)?;
+        Ok(())
     }
 
     #[cfg(target_os = "linux")]
     pub fn install_daemon() -> Result<(), Error> {
-        sudo::escalate_if_needed().expect("Root access needed to scan devices");
+        sudo::escalate_if_needed().map_err(|_| Error::Root)?;
+
+        // run as whoever installed it, not as root after escalating
+        let user = std::env::var("SUDO_USER").unwrap_or_else(|_| whoami::username());
+
+        // the unit only gets write access to the service user's config dir
+        let home = Self::home_dir(&user);
+        let config_dir = match &home {
+            Some(home) => home.join(".config").join(Self::NAME),
+            None => Self::config_dir()?,
+        };
+        let bin_path = std::env::current_exe()?.to_string_lossy().to_string();
 
         let systemd_file = SystemdServiceFile {
             bin_name: Self::BIN_NAME.to_string(),
-            bin_path: std::env::current_exe()?.to_string_lossy().to_string(),
+            bin_path: bin_path.clone(),
             description: env!("CARGO_PKG_DESCRIPTION").to_string(),
-            user: whoami::username(),
+            config_dir: config_dir.to_string_lossy().to_string(),
+            watchdog_secs: Self::WATCHDOG.as_secs(),
+            user,
         };
 
-        let service_name = format!("{}.service", Self::NAME);
+        let service_name = Self::service_name();
         let path = format!("/etc/systemd/system/{}", &service_name);
 
         let contents = systemd_file.render()?;
         std::fs::write(path, contents)?;
 
+        let (update_service, update_timer) = Self::update_unit_names();
+        let update_file = UpdateServiceFile {
+            description: env!("CARGO_PKG_DESCRIPTION").to_string(),
+            bin_name: Self::BIN_NAME.to_string(),
+            bin_path,
+            home: home
+                .map(|home| home.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        let timer_file = UpdateTimerFile {
+            description: env!("CARGO_PKG_DESCRIPTION").to_string(),
+        };
+        std::fs::write(
+            format!("/etc/systemd/system/{}", update_service),
+            update_file.render()?,
+        )?;
+        std::fs::write(
+            format!("/etc/systemd/system/{}", &update_timer),
+            timer_file.render()?,
+        )?;
+
+        let _ = std::process::Command::new("systemctl")
+            .arg("daemon-reload")
+            .output()?;
+
+        let _ = std::process::Command::new("systemctl")
+            .args(["enable", "--now", &update_timer])
+            .output()?;
+
         let _ = std::process::Command::new("systemctl")
             .arg("--now")
             .arg("enable")
@@ -120,11 +258,59 @@ impl Agent {
         Ok(())
     }
 
+    /// whether the unit is enabled and whether it's running, as systemctl reports them
+    #[cfg(target_os = "linux")]
+    pub fn service_state() -> Result<(String, String), Error> {
+        let service = Self::service_name();
+        let systemctl = |verb: &str| {
+            std::process::Command::new("systemctl")
+                .args([verb, &service])
+                .output()
+                .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        };
+        Ok((systemctl("is-enabled")?, systemctl("is-active")?))
+    }
+
+    /// a user's home directory from /etc/passwd
+    #[cfg(target_os = "linux")]
+    fn home_dir(user: &str) -> Option<PathBuf> {
+        std::fs::read_to_string("/etc/passwd")
+            .ok()?
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.first() == Some(&user))
+            .and_then(|fields| fields.get(5).map(PathBuf::from))
+    }
+
+    /// Tell systemd about our state, e.g. `READY=1` or `WATCHDOG=1`, if it's supervising us
+    #[cfg(target_os = "linux")]
+    pub fn sd_notify(state: &str) -> Result<(), Error> {
+        use std::os::{linux::net::SocketAddrExt, unix::net};
+
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => return Ok(()),
+        };
+
+        let addr = match path.strip_prefix('@') {
+            Some(name) => net::SocketAddr::from_abstract_name(name)?,
+            None => net::SocketAddr::from_pathname(&path)?,
+        };
+
+        net::UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn sd_notify(_state: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
     #[cfg(target_os = "linux")]
     pub fn uninstall_daemon() -> Result<(), Error> {
-        sudo::escalate_if_needed().expect("Root access needed to scan devices");
+        sudo::escalate_if_needed().map_err(|_| Error::Root)?;
 
-        let service_name = format!("{}.service", Self::NAME);
+        let service_name = Self::service_name();
 
         let _ = std::process::Command::new("systemctl")
             .arg("stop")
@@ -139,6 +325,14 @@ impl Agent {
         let path = format!("/etc/systemd/system/{}", &service_name);
         std::fs::remove_file(path)?;
 
+        let (update_service, update_timer) = Self::update_unit_names();
+        let _ = std::process::Command::new("systemctl")
+            .args(["disable", "--now", &update_timer])
+            .output()?;
+        for unit in [update_service, update_timer] {
+            let _ = std::fs::remove_file(format!("/etc/systemd/system/{}", unit));
+        }
+
         Ok(())
     }
 
@@ -172,4 +366,57 @@ struct SystemdServiceFile {
     bin_path: String,
     bin_name: String,
     user: String,
+    config_dir: String,
+    watchdog_secs: u64,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Template)]
+#[template(path = "update.service", escape = "none")]
+struct UpdateServiceFile {
+    description: String,
+    bin_name: String,
+    bin_path: String,
+    home: String,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Template)]
+#[template(path = "update.timer", escape = "none")]
+struct UpdateTimerFile {
+    description: String,
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sd_notify() {
+        let path = std::env::temp_dir().join(format!("reverseping-notify-{}", std::process::id()));
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+
+        Agent::sd_notify("WATCHDOG=1").unwrap();
+        let mut buf = [0u8; 32];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+
+        std::env::remove_var("NOTIFY_SOCKET");
+        let _ = std::fs::remove_file(path);
+
+        let unit = SystemdServiceFile {
+            description: "agent".to_string(),
+            bin_path: "/usr/bin/reverseping".to_string(),
+            bin_name: "reverseping".to_string(),
+            user: "alex".to_string(),
+            config_dir: "/home/alex/.config/reverseping".to_string(),
+            watchdog_secs: 600,
+        }
+        .render()
+        .unwrap();
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("WatchdogSec=600\n"));
+        assert!(unit.contains("ReadWritePaths=-/home/alex/.config/reverseping\n"));
+    }
 }
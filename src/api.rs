@@ -0,0 +1,419 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use hyper::{
+    header,
+    http::uri::Authority,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{Notify, RwLock};
+
+use crate::{dashboard, inventory::Inventory};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("local api failed: {0}")]
+    Http(#[from] hyper::Error),
+}
+
+/// The opt-in HTTP API other tools on this host can query
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "listen_default")]
+    pub listen: SocketAddr,
+    /// serve the html dashboard under `/ui`
+    #[serde(default = "dashboard_default")]
+    pub dashboard: bool,
+}
+
+fn listen_default() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 7676))
+}
+
+fn dashboard_default() -> bool {
+    true
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: listen_default(),
+            dashboard: dashboard_default(),
+        }
+    }
+}
+
+/// What the request handlers share with the daemon loop
+#[derive(Clone)]
+pub struct Context {
+    pub inventory: Arc<RwLock<Inventory>>,
+    /// wakes the daemon loop up to scan right away
+    pub scan_now: Arc<Notify>,
+    pub dashboard: bool,
+    /// requests have to name this address or localhost as their Host
+    pub listen: SocketAddr,
+}
+
+pub async fn serve(config: ApiConfig, context: Context) -> Result<(), Error> {
+    if !config.listen.ip().is_loopback() {
+        log::warn!(
+            "local api listening on {}, which other hosts can reach",
+            config.listen
+        );
+    }
+
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(handle(request, &context).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&config.listen)?.serve(make_service);
+    log::info!("local api listening on http://{}", config.listen);
+    Ok(server.await?)
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap_or_default()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &reverseping::ApiError { error: message })
+}
+
+/// A DNS rebinding page reaches the api under its own hostname, so only IP
+/// addresses the api listens on and localhost are accepted
+fn allowed_host(request: &Request<Body>, listen: SocketAddr) -> bool {
+    let authority = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let name = match &authority {
+        Some(authority) => authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+        None => return false,
+    };
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip == listen.ip() || listen.ip().is_unspecified(),
+        Err(_) => name.eq_ignore_ascii_case("localhost"),
+    }
+}
+
+/// Whether the page that sent a browser request was served by this api, none
+/// for clients that don't send `Origin` or `Referer` like curl
+fn same_origin(request: &Request<Body>) -> Option<bool> {
+    let headers = request.headers();
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))?;
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    let authority = source
+        .to_str()
+        .ok()
+        .and_then(|source| source.split_once("://"))
+        .and_then(|(_, rest)| rest.split('/').next());
+    Some(
+        authority.is_some_and(|authority| !host.is_empty() && authority.eq_ignore_ascii_case(host)),
+    )
+}
+
+async fn handle(request: Request<Body>, context: &Context) -> Response<Body> {
+    let path: Vec<&str> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    if !allowed_host(&request, context.listen) {
+        return error(StatusCode::FORBIDDEN, "unexpected Host header");
+    }
+    // other sites' forms can post here, the dashboard's own form always has an origin
+    if request.method() == Method::POST {
+        match same_origin(&request) {
+            Some(false) => return error(StatusCode::FORBIDDEN, "cross-site request"),
+            None if path.first() == Some(&"ui") => {
+                return error(StatusCode::FORBIDDEN, "missing Origin header")
+            }
+            _ => {}
+        }
+    }
+
+    if context.dashboard {
+        match (request.method(), path.as_slice()) {
+            (&Method::GET, []) => return dashboard::redirect("/ui"),
+            (&Method::GET, ["ui"]) => return dashboard::index(context).await,
+            (&Method::GET, ["ui", "devices", mac]) => return dashboard::device(context, mac).await,
+            (&Method::POST, ["ui", "scan"]) => {
+                context.scan_now.notify_one();
+                return dashboard::redirect("/ui");
+            }
+            (_, ["ui", ..]) => return error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            _ => {}
+        }
+    }
+
+    match (request.method(), path.as_slice()) {
+        (&Method::GET, ["health"]) => health(context).await,
+        (&Method::GET, ["devices"]) => devices(context).await,
+        (&Method::GET, ["devices", mac]) => device(context, mac).await,
+        (&Method::POST, ["scan"]) => {
+            context.scan_now.notify_one();
+            json(
+                StatusCode::ACCEPTED,
+                &serde_json::json!({ "status": "scan requested" }),
+            )
+        }
+        (_, ["health"]) | (_, ["devices", ..]) | (_, ["scan"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// 200 while scans succeed, 503 once the last one failed
+async fn health(context: &Context) -> Response<Body> {
+    let inventory = context.inventory.read().await;
+    let status = &inventory.status;
+
+    let code = if status.scan_error.is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    json(
+        code,
+        &serde_json::json!({
+            "status": if code == StatusCode::OK { "ok" } else { "error" },
+            "version": env!("CARGO_PKG_VERSION"),
+            "daemon": status,
+        }),
+    )
+}
+
+async fn devices(context: &Context) -> Response<Body> {
+    let inventory = context.inventory.read().await;
+    let mut devices: Vec<_> = inventory.devices.values().collect();
+    devices.sort_by_key(|d| d.local_address);
+    json(StatusCode::OK, &devices)
+}
+
+async fn device(context: &Context, mac: &str) -> Response<Body> {
+    let mac = mac.replace('-', ":").to_lowercase();
+    let inventory = context.inventory.read().await;
+
+    let current = inventory.devices.values().find(|d| d.mac == mac);
+    let samples = inventory.history.samples.get(&mac);
+    if current.is_none() && samples.is_none() {
+        return error(StatusCode::NOT_FOUND, "unknown device");
+    }
+
+    let events: Vec<_> = inventory
+        .history
+        .events
+        .iter()
+        .filter(|e| e.event.concerns_mac(&mac))
+        .collect();
+
+    json(
+        StatusCode::OK,
+        &serde_json::json!({
+            "mac": mac,
+            "device": current,
+            "samples": samples,
+            "events": events,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveredDevice;
+
+    #[tokio::test]
+    async fn test_routes() {
+        let mut inventory = Inventory::default();
+        inventory.devices.insert(
+            "aa:aa:aa:aa:aa:01".to_string(),
+            DiscoveredDevice {
+                local_address: "10.0.0.2".parse().unwrap(),
+                ping_ms: 3,
+                hostname: None,
+                mac: "aa:aa:aa:aa:aa:01".to_string(),
+                vendor: None,
+                meta: None,
+            },
+        );
+        let context = Context {
+            inventory: Arc::new(RwLock::new(inventory)),
+            scan_now: Arc::new(Notify::new()),
+            dashboard: true,
+            listen: listen_default(),
+        };
+
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::HOST, "127.0.0.1:7676")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let status = |response: Response<Body>| response.status();
+        assert_eq!(
+            status(handle(request(Method::GET, "/devices/AA-AA-AA-AA-AA-01"), &context).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(handle(request(Method::GET, "/devices/aa:aa:aa:aa:aa:02"), &context).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(
+                handle(
+                    request(Method::GET, "/ui/devices/aa-aa-aa-aa-aa-01"),
+                    &context
+                )
+                .await
+            ),
+            StatusCode::OK
+        );
+        let mut from_dashboard = request(Method::POST, "/ui/scan");
+        from_dashboard
+            .headers_mut()
+            .insert(header::ORIGIN, "http://127.0.0.1:7676".parse().unwrap());
+        assert_eq!(
+            status(handle(from_dashboard, &context).await),
+            StatusCode::SEE_OTHER
+        );
+        assert_eq!(
+            status(handle(request(Method::GET, "/scan"), &context).await),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        assert_eq!(
+            status(handle(request(Method::POST, "/scan"), &context).await),
+            StatusCode::ACCEPTED
+        );
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            context.scan_now.notified(),
+        )
+        .await
+        .expect("scan wasn't requested");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_other_sites() {
+        let context = Context {
+            inventory: Arc::new(RwLock::new(Inventory::default())),
+            scan_now: Arc::new(Notify::new()),
+            dashboard: true,
+            listen: listen_default(),
+        };
+        let request = |method: Method, uri: &str, headers: &[(header::HeaderName, &str)]| {
+            let mut request = Request::builder().method(method).uri(uri);
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let status = |response: Response<Body>| response.status();
+
+        // dns rebinding
+        assert_eq!(
+            status(
+                handle(
+                    request(
+                        Method::GET,
+                        "/devices",
+                        &[(header::HOST, "evil.example:7676")]
+                    ),
+                    &context
+                )
+                .await
+            ),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(handle(request(Method::GET, "/devices", &[]), &context).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(
+                handle(
+                    request(Method::GET, "/devices", &[(header::HOST, "localhost:7676")]),
+                    &context
+                )
+                .await
+            ),
+            StatusCode::OK
+        );
+
+        // cross-site forms
+        let evil = [
+            (header::HOST, "127.0.0.1:7676"),
+            (header::ORIGIN, "https://evil.example"),
+        ];
+        assert_eq!(
+            status(handle(request(Method::POST, "/scan", &evil), &context).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(handle(request(Method::POST, "/ui/scan", &evil), &context).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(
+                handle(
+                    request(
+                        Method::POST,
+                        "/ui/scan",
+                        &[(header::HOST, "127.0.0.1:7676")]
+                    ),
+                    &context
+                )
+                .await
+            ),
+            StatusCode::FORBIDDEN
+        );
+        let referred = [
+            (header::HOST, "127.0.0.1:7676"),
+            (
+                header::REFERER,
+                "http://127.0.0.1:7676/ui/devices/aa-aa-aa-aa-aa-01",
+            ),
+        ];
+        assert_eq!(
+            status(handle(request(Method::POST, "/ui/scan", &referred), &context).await),
+            StatusCode::SEE_OTHER
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use reverseping::{CertificateEvent, CertificateInfo};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+use x509_parser::{extensions::GeneralName, prelude::X509Certificate};
+
+use crate::{
+    agent::Agent,
+    discovery::{DeviceName, DiscoveredDevice},
+    health,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("tls handshake failed: {0}")]
+    Tls(#[from] native_tls::Error),
+    #[error("no certificate presented")]
+    NoCertificate,
+    #[error("invalid certificate: {0}")]
+    Parse(String),
+    #[error("{0}")]
+    Resolve(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("certificate state error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// ports tried on every discovered device
+    #[serde(default = "ports_default")]
+    pub ports: Vec<u16>,
+    #[serde(default = "scan_devices_default")]
+    pub scan_devices: bool,
+    /// extra `host` or `host:port` targets, port 443 if left out
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// raise an event once a certificate is this close to expiring
+    #[serde(default = "warn_days_default")]
+    pub warn_days: i64,
+}
+
+fn ports_default() -> Vec<u16> {
+    vec![443, 8443]
+}
+
+fn scan_devices_default() -> bool {
+    true
+}
+
+fn warn_days_default() -> i64 {
+    30
+}
+
+impl Default for CertConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ports: ports_default(),
+            scan_devices: scan_devices_default(),
+            targets: vec![],
+            warn_days: warn_days_default(),
+        }
+    }
+}
+
+impl CertConfig {
+    const TIMEOUT: Duration = Duration::from_secs(3);
+    const CONCURRENCY: usize = 64;
+}
+
+/// Fetch the certificates from every configured target and open TLS port on the discovered devices
+pub async fn collect(
+    config: &CertConfig,
+    devices: &HashMap<DeviceName, DiscoveredDevice>,
+) -> Vec<CertificateInfo> {
+    let mut endpoints: Vec<(String, u16, bool)> = config
+        .targets
+        .iter()
+        .map(|target| {
+            let (host, port) = split_target(target);
+            (host, port, true)
+        })
+        .collect();
+
+    if config.scan_devices {
+        for device in devices.values() {
+            for port in &config.ports {
+                endpoints.push((device.local_address.to_string(), *port, false));
+            }
+        }
+    }
+
+    futures::stream::iter(endpoints)
+        .map(|(host, port, configured)| async move {
+            match fetch(&host, port).await {
+                Ok(cert) => Some(cert),
+                Err(err) => {
+                    // closed ports on discovered devices are expected, failing targets aren't
+                    if configured {
+                        log::error!("certificate check {}:{} failed: {}", host, port, err);
+                    }
+                    None
+                }
+            }
+        })
+        .buffer_unordered(CertConfig::CONCURRENCY)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await
+}
+
+fn split_target(target: &str) -> (String, u16) {
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => match port.parse() {
+            Ok(port) => (
+                host.trim_matches(|c| c == '[' || c == ']').to_string(),
+                port,
+            ),
+            Err(_) => (target.to_string(), 443),
+        },
+        _ => (
+            target.trim_matches(|c| c == '[' || c == ']').to_string(),
+            443,
+        ),
+    }
+}
+
+/// Connect to a TLS port and read its leaf certificate, whether or not we'd trust it
+pub async fn fetch(host: &str, port: u16) -> Result<CertificateInfo, Error> {
+    tokio::time::timeout(CertConfig::TIMEOUT, fetch_inner(host, port))
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+async fn fetch_inner(host: &str, port: u16) -> Result<CertificateInfo, Error> {
+    let is_ip = host.parse::<IpAddr>().is_ok();
+    let ip = health::resolve(host).await.map_err(Error::Resolve)?;
+    let stream = TcpStream::connect(SocketAddr::new(ip, port)).await?;
+
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .use_sni(!is_ip)
+        .build()?;
+    let tls = tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, stream)
+        .await?;
+
+    let der = tls
+        .get_ref()
+        .peer_certificate()?
+        .ok_or(Error::NoCertificate)?
+        .to_der()?;
+
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(&der).map_err(|e| Error::Parse(e.to_string()))?;
+
+    certificate_info(host, port, &cert, Utc::now())
+}
+
+fn certificate_info(
+    host: &str,
+    port: u16,
+    cert: &X509Certificate,
+    now: DateTime<Utc>,
+) -> Result<CertificateInfo, Error> {
+    let not_after = Utc
+        .timestamp_opt(cert.validity().not_after.timestamp(), 0)
+        .single()
+        .ok_or_else(|| Error::Parse("invalid expiry date".to_string()))?;
+
+    let sans = cert
+        .subject_alternative_name()
+        .map_err(|e| Error::Parse(e.to_string()))?
+        .map(|ext| {
+            ext.value
+                .general_names
+                .iter()
+                .map(|name| match name {
+                    GeneralName::DNSName(dns) => dns.to_string(),
+                    GeneralName::IPAddress([a, b, c, d]) => {
+                        IpAddr::from([*a, *b, *c, *d]).to_string()
+                    }
+                    other => other.to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let subject = cert.subject().to_string();
+    let issuer = cert.issuer().to_string();
+
+    Ok(CertificateInfo {
+        host: host.to_string(),
+        port,
+        self_signed: subject == issuer,
+        subject,
+        issuer,
+        sans,
+        not_after,
+        days_remaining: (not_after - now).num_days(),
+    })
+}
+
+/// Which certificates were already reported, so each one only raises an event once per state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CertState {
+    reported: HashMap<String, String>,
+}
+
+impl CertState {
+    const STATE_FILE: &'static str = "certificates.json";
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn check(
+        &mut self,
+        config: &CertConfig,
+        certs: &[CertificateInfo],
+    ) -> Vec<CertificateEvent> {
+        let mut events = vec![];
+
+        for cert in certs {
+            let key = format!("{}:{}", cert.host, cert.port);
+
+            let event = if cert.days_remaining < 0 {
+                CertificateEvent::Expired {
+                    host: cert.host.clone(),
+                    port: cert.port,
+                    subject: cert.subject.clone(),
+                    not_after: cert.not_after,
+                }
+            } else if cert.days_remaining <= config.warn_days {
+                CertificateEvent::Expiring {
+                    host: cert.host.clone(),
+                    port: cert.port,
+                    subject: cert.subject.clone(),
+                    not_after: cert.not_after,
+                    days_remaining: cert.days_remaining,
+                }
+            } else {
+                self.reported.remove(&key);
+                continue;
+            };
+
+            let state = match event {
+                CertificateEvent::Expiring { .. } => format!("expiring {}", cert.not_after),
+                CertificateEvent::Expired { .. } => format!("expired {}", cert.not_after),
+            };
+
+            if self.reported.get(&key) != Some(&state) {
+                self.reported.insert(key, state);
+                events.push(event);
+            }
+        }
+
+        events
+    }
+}
+
+/// Compare collected certificates against the saved state and return new expiry events
+pub fn expiry_events(
+    config: &CertConfig,
+    certs: &[CertificateInfo],
+) -> Result<Vec<CertificateEvent>, Error> {
+    let mut state = CertState::load()?;
+    let events = state.check(config, certs);
+    state.save()?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(days_remaining: i64) -> CertificateInfo {
+        CertificateInfo {
+            host: "10.0.0.20".to_string(),
+            port: 443,
+            subject: "CN=nas".to_string(),
+            issuer: "CN=nas".to_string(),
+            sans: vec![],
+            not_after: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            days_remaining,
+            self_signed: true,
+        }
+    }
+
+    #[test]
+    fn test_expiry_events() {
+        let config = CertConfig::default();
+        let mut state = CertState::default();
+
+        assert!(state.check(&config, &[cert(90)]).is_empty());
+        assert_eq!(state.check(&config, &[cert(20)]).len(), 1);
+        assert!(state.check(&config, &[cert(19)]).is_empty());
+
+        let events = state.check(&config, &[cert(-1)]);
+        assert!(matches!(
+            events.as_slice(),
+            [CertificateEvent::Expired { .. }]
+        ));
+        assert!(state.check(&config, &[cert(-2)]).is_empty());
+    }
+
+    #[test]
+    fn test_split_target() {
+        assert_eq!(split_target("nas.local"), ("nas.local".to_string(), 443));
+        assert_eq!(
+            split_target("nas.local:5001"),
+            ("nas.local".to_string(), 5001)
+        );
+        assert_eq!(
+            split_target("[fe80::1]:8443"),
+            ("fe80::1".to_string(), 8443)
+        );
+        assert_eq!(split_target("fe80::1"), ("fe80::1".to_string(), 443));
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_fetch() {
+        let cert = fetch("example.com", 443).await.expect("failed to fetch");
+        assert!(cert.days_remaining > 0);
+        assert!(cert.sans.iter().any(|san| san.contains("example.com")));
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use reverseping::CheckResult;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use trust_dns_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    proto::{rr::RecordType, xfer::DnsRequestOptions},
+    TokioAsyncResolver,
+};
+
+use crate::{discovery::ping, health};
+
+/// A service check run on every scan, configured as a `[[checks]]` entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckConfig {
+    pub name: String,
+    #[serde(default = "timeout_default")]
+    pub timeout_secs: u64,
+    #[serde(flatten)]
+    pub kind: CheckKind,
+}
+
+fn timeout_default() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CheckKind {
+    /// GET a URL, expecting a status code and optionally a substring of the body
+    Http {
+        url: String,
+        #[serde(default = "expect_status_default")]
+        expect_status: u16,
+        expect_body: Option<String>,
+        /// accept self-signed and expired certificates
+        #[serde(default)]
+        insecure: bool,
+    },
+    /// Open a TCP connection
+    Tcp { host: String, port: u16 },
+    /// Resolve a name, optionally expecting a specific answer
+    Dns {
+        query: String,
+        #[serde(default = "record_type_default")]
+        record_type: String,
+        /// resolver to ask instead of the system one
+        server: Option<IpAddr>,
+        expect: Option<String>,
+    },
+    /// Send an ICMP echo request
+    Icmp { host: String },
+}
+
+fn expect_status_default() -> u16 {
+    200
+}
+
+fn record_type_default() -> String {
+    "A".to_string()
+}
+
+impl CheckKind {
+    fn kind(&self) -> &'static str {
+        match self {
+            CheckKind::Http { .. } => "http",
+            CheckKind::Tcp { .. } => "tcp",
+            CheckKind::Dns { .. } => "dns",
+            CheckKind::Icmp { .. } => "icmp",
+        }
+    }
+
+    fn target(&self) -> String {
+        match self {
+            CheckKind::Http { url, .. } => url.clone(),
+            CheckKind::Tcp { host, port } => format!("{}:{}", host, port),
+            CheckKind::Dns { query, .. } => query.clone(),
+            CheckKind::Icmp { host } => host.clone(),
+        }
+    }
+}
+
+/// Run every configured check concurrently
+pub async fn run_all(checks: &[CheckConfig]) -> Vec<CheckResult> {
+    futures::future::join_all(checks.iter().map(run)).await
+}
+
+pub async fn run(check: &CheckConfig) -> CheckResult {
+    let timeout = Duration::from_secs(check.timeout_secs);
+    let start = Instant::now();
+
+    let outcome = match tokio::time::timeout(timeout, run_kind(&check.kind, timeout)).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(format!("timed out after {}s", check.timeout_secs)),
+    };
+
+    let latency_ms = Some(start.elapsed().as_secs_f64() * 1000.0);
+
+    CheckResult {
+        name: check.name.clone(),
+        kind: check.kind.kind().to_string(),
+        target: check.kind.target(),
+        up: outcome.is_ok(),
+        latency_ms: outcome.as_ref().ok().and(latency_ms),
+        error: outcome.err(),
+    }
+}
+
+async fn run_kind(kind: &CheckKind, timeout: Duration) -> Result<(), String> {
+    match kind {
+        CheckKind::Http {
+            url,
+            expect_status,
+            expect_body,
+            insecure,
+        } => {
+            let client = reqwest::Client::builder()
+                .timeout(timeout)
+                .danger_accept_invalid_certs(*insecure)
+                .build()
+                .map_err(|e| e.to_string())?;
+
+            let response = client.get(url).send().await.map_err(|e| e.to_string())?;
+            let status = response.status().as_u16();
+            if status != *expect_status {
+                return Err(format!("expected status {}, got {}", expect_status, status));
+            }
+
+            if let Some(expected) = expect_body {
+                let body = response.text().await.map_err(|e| e.to_string())?;
+                if !body.contains(expected.as_str()) {
+                    return Err(format!("body does not contain {:?}", expected));
+                }
+            }
+            Ok(())
+        }
+        CheckKind::Tcp { host, port } => {
+            let ip = health::resolve(host).await?;
+            TcpStream::connect(SocketAddr::new(ip, *port))
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        CheckKind::Dns {
+            query,
+            record_type,
+            server,
+            expect,
+        } => {
+            let answers = dns_lookup(query, record_type, *server).await?;
+            match expect {
+                Some(expected) if !answers.iter().any(|a| a.trim_end_matches('.') == expected) => {
+                    Err(format!(
+                        "expected {}, got [{}]",
+                        expected,
+                        answers.join(", ")
+                    ))
+                }
+                _ if answers.is_empty() => Err("no answers".to_string()),
+                _ => Ok(()),
+            }
+        }
+        CheckKind::Icmp { host } => {
+            let ip = health::resolve(host).await?;
+            ping::ping(ip).await.map_err(|e| e.to_string())?;
+            Ok(())
+        }
+    }
+}
+
+async fn dns_lookup(
+    query: &str,
+    record_type: &str,
+    server: Option<IpAddr>,
+) -> Result<Vec<String>, String> {
+    let record_type = RecordType::from_str(record_type).map_err(|e| e.to_string())?;
+
+    let (config, opts) = match server {
+        Some(ip) => {
+            let name_server = NameServerConfig {
+                socket_addr: SocketAddr::from((ip, 53)),
+                protocol: Protocol::Udp,
+                tls_dns_name: None,
+                trust_nx_responses: true,
+            };
+            (
+                ResolverConfig::from_parts(None, vec![], vec![name_server]),
+                ResolverOpts::default(),
+            )
+        }
+        None => trust_dns_resolver::system_conf::read_system_conf().map_err(|e| e.to_string())?,
+    };
+
+    let resolver = TokioAsyncResolver::tokio(config, opts).map_err(|e| e.to_string())?;
+    let lookup = resolver
+        .lookup(query, record_type, DnsRequestOptions::default())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(lookup.iter().map(|rdata| rdata.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_config() {
+        #[derive(Deserialize)]
+        struct Checks {
+            checks: Vec<CheckConfig>,
+        }
+
+        let Checks { checks } = toml::from_str(
+            r#"
+            [[checks]]
+            name = "intranet"
+            type = "http"
+            url = "https://intranet.local/health"
+            expect_body = "ok"
+
+            [[checks]]
+            name = "nas dns"
+            type = "dns"
+            query = "nas.corp"
+            expect = "10.0.0.20"
+            timeout_secs = 2
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &checks[0].kind,
+            CheckKind::Http { expect_status: 200, expect_body: Some(body), insecure: false, .. } if body == "ok"
+        ));
+        assert_eq!(checks[0].timeout_secs, 10);
+        assert_eq!(checks[1].kind.kind(), "dns");
+        assert_eq!(checks[1].kind.target(), "nas.corp");
+        assert_eq!(checks[1].timeout_secs, 2);
+    }
+
+    /// Serves one canned http response per connection, or nothing at all when `reply` is none
+    async fn listener(reply: Option<&'static str>) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = socket.read(&mut request).await;
+                    match reply {
+                        Some(reply) => {
+                            let _ = socket.write_all(reply.as_bytes()).await;
+                        }
+                        None => tokio::time::sleep(Duration::from_secs(30)).await,
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn check(kind: CheckKind, timeout_secs: u64) -> CheckConfig {
+        CheckConfig {
+            name: "test".to_string(),
+            timeout_secs,
+            kind,
+        }
+    }
+
+    fn http(addr: SocketAddr, expect_body: Option<&str>) -> CheckKind {
+        CheckKind::Http {
+            url: format!("http://{}/health", addr),
+            expect_status: 200,
+            expect_body: expect_body.map(String::from),
+            insecure: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_checks() {
+        let up = listener(Some(
+            "HTTP/1.1 200 OK\r\ncontent-length: 7\r\nconnection: close\r\n\r\nall ok\n",
+        ))
+        .await;
+        let hung = listener(None).await;
+        // bound then dropped, nothing listens there anymore
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let results = run_all(&[
+            check(http(up, Some("ok")), 5),
+            check(http(up, Some("degraded")), 5),
+            check(
+                CheckKind::Tcp {
+                    host: "127.0.0.1".to_string(),
+                    port: up.port(),
+                },
+                5,
+            ),
+            check(
+                CheckKind::Tcp {
+                    host: "127.0.0.1".to_string(),
+                    port: closed.port(),
+                },
+                5,
+            ),
+            check(http(hung, None), 1),
+        ])
+        .await;
+
+        assert!(results[0].up, "{:?}", results[0].error);
+        assert!(results[0].latency_ms.is_some());
+        assert_eq!(results[0].kind, "http");
+
+        assert!(!results[1].up);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("body does not contain \"degraded\"")
+        );
+        assert!(results[1].latency_ms.is_none());
+
+        assert!(results[2].up, "{:?}", results[2].error);
+        assert_eq!(results[2].target, format!("127.0.0.1:{}", up.port()));
+        assert!(!results[3].up);
+
+        assert!(!results[4].up);
+        // the client's own timeout can win the race against the check's
+        assert!(results[4].error.as_deref().unwrap().contains("timed out"));
+    }
+}
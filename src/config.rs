@@ -0,0 +1,288 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::{
+    agent::{self, Agent, AgentConfig},
+    transmit,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("{}: {error}", path.display())]
+    File {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    #[error("invalid config: {0}")]
+    Decoding(#[from] toml::de::Error),
+    #[error("{0}")]
+    Encoding(#[from] toml::ser::Error),
+    #[error("invalid --set {0}, expected key=value")]
+    Flag(String),
+}
+
+/// Shared by every user on the host, the user config overrides it
+pub const SYSTEM_FILE: &str = "/etc/reverseping/config.toml";
+/// `REVERSEPING_API__LISTEN` sets `api.listen`, `__` separates nested keys
+const ENV_PREFIX: &str = "REVERSEPING_";
+/// the only override that existed before the layered config
+const LEGACY_ENV: &[(&str, &str)] = &[("API_ORIGIN", "api_origin")];
+
+/// Where a config value was set, later layers win
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag => write!(f, "--set"),
+        }
+    }
+}
+
+/// The merged config and the layer each value came from
+#[derive(Debug)]
+pub struct Effective {
+    pub config: AgentConfig,
+    values: BTreeMap<String, (toml::Value, Source)>,
+}
+
+impl Effective {
+    /// The merged config, as long as it names an agent to report to
+    pub fn agent(self) -> Result<AgentConfig, Error> {
+        if self.config.agent.trim().is_empty() {
+            return Err(agent::Error::AgentNotConfigured.into());
+        }
+        Ok(self.config)
+    }
+
+    /// `key = value  # source` for every setting
+    pub fn print(&self) {
+        let lines: Vec<_> = self
+            .values
+            .iter()
+            .filter(|(_, (value, _))| !matches!(value, toml::Value::Table(t) if t.is_empty()))
+            .map(|(key, (value, source))| (format!("{} = {}", key, shown(key, value)), source))
+            .collect();
+        let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+        for (line, source) in lines {
+            println!("{:<width$}  # {}", line, source, width = width);
+        }
+    }
+}
+
+/// A value as it's safe to print or log, without the proxy's password
+pub fn shown(key: &str, value: &toml::Value) -> String {
+    match (key, value) {
+        ("transport.proxy", toml::Value::String(url)) => {
+            toml::Value::String(transmit::redact_proxy(url)).to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Files merged under the env vars and flags, lowest precedence first
+pub fn files() -> Result<Vec<PathBuf>, Error> {
+    Ok(vec![PathBuf::from(SYSTEM_FILE), Agent::config_file()?])
+}
+
+/// Merge defaults, the system and user config files, `REVERSEPING_*` env vars
+/// and `--set key=value` flags
+pub fn load(flags: &[String]) -> Result<Effective, Error> {
+    merge(&files()?, std::env::vars(), flags)
+}
+
+/// Merge defaults with `files`, then the config vars among `vars` and `flags`
+fn merge(
+    files: &[PathBuf],
+    vars: impl IntoIterator<Item = (String, String)>,
+    flags: &[String],
+) -> Result<Effective, Error> {
+    let mut values = BTreeMap::new();
+    flatten(
+        "",
+        toml::Value::try_from(AgentConfig::default())?,
+        &mut |key, value| {
+            values.insert(key, (value, Source::Default));
+        },
+    );
+
+    for path in files {
+        if !path.exists() {
+            continue;
+        }
+        let contents = std::fs::read_to_string(path)?;
+        let table: toml::Value = toml::from_str(&contents).map_err(|error| Error::File {
+            path: path.clone(),
+            error,
+        })?;
+        flatten("", table, &mut |key, value| {
+            values.insert(key, (value, Source::File(path.clone())));
+        });
+    }
+
+    let vars: Vec<(String, String)> = vars.into_iter().collect();
+    let mut env: Vec<(String, String, String)> = LEGACY_ENV
+        .iter()
+        .filter_map(|(var, key)| {
+            let (_, value) = vars.iter().find(|(name, _)| name == var)?;
+            Some((var.to_string(), key.to_string(), value.clone()))
+        })
+        .collect();
+    let mut prefixed: Vec<_> = vars
+        .into_iter()
+        .filter_map(|(var, value)| {
+            let key = var
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace("__", ".");
+            Some((var, key, value))
+        })
+        .collect();
+    prefixed.sort();
+    env.extend(prefixed);
+
+    for (var, key, raw) in env {
+        let value = parse(values.get(&key).map(|(v, _)| v), &raw);
+        set(&mut values, key, value, Source::Env(var));
+    }
+
+    for flag in flags {
+        let (key, raw) = flag
+            .split_once('=')
+            .ok_or_else(|| Error::Flag(flag.clone()))?;
+        let key = key.trim().to_string();
+        let value = parse(values.get(&key).map(|(v, _)| v), raw.trim());
+        set(&mut values, key, value, Source::Flag);
+    }
+
+    let mut table = toml::value::Table::new();
+    for (key, (value, _)) in &values {
+        insert(&mut table, key, value.clone());
+    }
+
+    Ok(Effective {
+        config: toml::Value::Table(table).try_into()?,
+        values,
+    })
+}
+
+/// Overriding a table or array element replaces everything under it
+fn set(
+    values: &mut BTreeMap<String, (toml::Value, Source)>,
+    key: String,
+    value: toml::Value,
+    source: Source,
+) {
+    let nested = format!("{}.", key);
+    values.retain(|existing, _| !existing.starts_with(&nested));
+    flatten(&key, value, &mut |key, value| {
+        values.insert(key, (value, source.clone()));
+    });
+}
+
+/// Read an override as toml, unless the setting it replaces is a string
+fn parse(current: Option<&toml::Value>, raw: &str) -> toml::Value {
+    if let Some(toml::Value::String(_)) = current {
+        return toml::Value::String(raw.to_string());
+    }
+    toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+fn flatten(prefix: &str, value: toml::Value, out: &mut dyn FnMut(String, toml::Value)) {
+    match value {
+        toml::Value::Table(table) if !table.is_empty() || prefix.is_empty() => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, out);
+            }
+        }
+        value => out(prefix.to_string(), value),
+    }
+}
+
+fn insert(table: &mut toml::value::Table, key: &str, value: toml::Value) {
+    match key.split_once('.') {
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::value::Table::new());
+            }
+            if let toml::Value::Table(nested) = entry {
+                insert(nested, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let dir = std::env::temp_dir().join(format!("reverseping-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (system, user) = (dir.join("system.toml"), dir.join("user.toml"));
+        std::fs::write(&system, "agent = \"system\"\nscan_interval_secs = 120\n").unwrap();
+        std::fs::write(&user, "agent = \"user\"\n").unwrap();
+        let files = [system.clone(), user.clone(), dir.join("missing.toml")];
+        let vars = || {
+            vec![
+                (
+                    "REVERSEPING_API__LISTEN".to_string(),
+                    "0.0.0.0:8080".to_string(),
+                ),
+                ("API_ORIGIN".to_string(), "https://api.example".to_string()),
+                ("HOME".to_string(), "/root".to_string()),
+            ]
+        };
+
+        let effective = merge(&files, vars(), &["api.enabled=true".to_string()]).unwrap();
+
+        assert_eq!(effective.config.agent, "user");
+        assert_eq!(effective.values["agent"].1, Source::File(user));
+        assert_eq!(effective.config.scan_interval_secs, 120);
+        assert_eq!(
+            effective.values["scan_interval_secs"].1,
+            Source::File(system)
+        );
+        assert_eq!(effective.config.api_origin, "https://api.example");
+        assert!(effective.config.api.enabled);
+        assert_eq!(effective.config.api.listen.port(), 8080);
+        assert_eq!(
+            effective.values["api.listen"].1,
+            Source::Env("REVERSEPING_API__LISTEN".to_string())
+        );
+        assert_eq!(effective.values["api.enabled"].1, Source::Flag);
+        assert_eq!(effective.values["api.dashboard"].1, Source::Default);
+
+        assert!(merge(&files, vars(), &["api.enabled".to_string()]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::{io::Write, path::PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use reverseping::AgentCredential;
+use thiserror::Error;
+
+use crate::{agent::Agent, transmit};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("unreadable credential file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Transmit(#[from] transmit::Error),
+    #[error("not enrolled, run `reverseping enroll <token>` first")]
+    NotEnrolled,
+}
+
+const FILE: &str = "credential.json";
+/// rotate once the credential has less than this left
+const ROTATE_BEFORE_DAYS: i64 = 7;
+
+pub fn path() -> Result<PathBuf, Error> {
+    Ok(Agent::data_file(FILE)?)
+}
+
+/// The stored credential, none for agents set up with a bare agent id
+pub fn load() -> Result<Option<AgentCredential>, Error> {
+    let path = path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+}
+
+/// Replace the stored credential, only the owner can read it
+pub fn save(credential: &AgentCredential) -> Result<(), Error> {
+    let path = path()?;
+    let tmp = path.with_extension("tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    // the mode only applies to new files, a leftover tmp file could have any
+    let _ = std::fs::remove_file(&tmp);
+    let mut file = options.open(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(credential)?)?;
+    file.sync_all()?;
+
+    // a crash mid write leaves the old credential in place, not half of the new one
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn remove() -> Result<(), Error> {
+    let path = path()?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// whether the credential expires soon enough to rotate it
+pub fn due(credential: &AgentCredential, now: DateTime<Utc>) -> bool {
+    credential
+        .expires_at
+        .map(|expires| expires - now < Duration::days(ROTATE_BEFORE_DAYS))
+        .unwrap_or(false)
+}
+
+/// Swap the stored credential for a new one, the API stops accepting the old
+/// one so the transmitter switches over too
+pub async fn rotate(transmitter: &mut transmit::Transmitter) -> Result<(), Error> {
+    if transmitter.credential().is_none() {
+        return Err(Error::NotEnrolled);
+    }
+    let credential = transmitter.rotate_credential().await?;
+    // the API already dropped the old one, keep reporting with the new one either way
+    transmitter.set_credential(credential.clone());
+    if let Err(err) = save(&credential) {
+        log::error!(
+            "rotated the credential but couldn't save it, enroll again before the agent restarts: {}",
+            err
+        );
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Revoke the credential with the API, then forget it
+pub async fn revoke(transmitter: &transmit::Transmitter) -> Result<(), Error> {
+    if transmitter.credential().is_none() {
+        return Err(Error::NotEnrolled);
+    }
+    transmitter.revoke_credential().await?;
+    remove()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_due() {
+        let now = Utc::now();
+        let credential = |expires_at| AgentCredential {
+            agent_id: "agent".to_string(),
+            secret: "secret".to_string(),
+            expires_at,
+        };
+
+        assert!(!due(&credential(None), now));
+        assert!(!due(&credential(Some(now + Duration::days(30))), now));
+        assert!(due(&credential(Some(now + Duration::days(2))), now));
+        assert!(due(&credential(Some(now - Duration::days(1))), now));
+    }
+}
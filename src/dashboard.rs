@@ -0,0 +1,256 @@
+use std::{collections::BTreeSet, net::IpAddr};
+
+use askama::Template;
+use chrono::{DateTime, Local, Utc};
+use hyper::{header, Body, Response, StatusCode};
+use reverseping::Event;
+
+use crate::{
+    api::Context,
+    events::DeviceTracker,
+    inventory::{Inventory, TimedEvent},
+    status,
+};
+
+/// the header every page shows
+struct StatusView {
+    last_scan: String,
+    scan_error: String,
+    transmit: String,
+}
+
+struct DeviceRow {
+    mac: String,
+    ip: String,
+    hostname: String,
+    vendor: String,
+    latency: String,
+    uptime: String,
+    last_seen: String,
+    up: bool,
+}
+
+struct EventRow {
+    at: String,
+    category: &'static str,
+    text: String,
+}
+
+struct SampleRow {
+    at: String,
+    ip: String,
+    latency: String,
+    up: bool,
+}
+
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+struct DashboardPage {
+    status: StatusView,
+    rows: Vec<DeviceRow>,
+    events: Vec<EventRow>,
+    up: usize,
+    down: usize,
+}
+
+#[derive(Template)]
+#[template(path = "device.html")]
+struct DevicePage {
+    status: StatusView,
+    row: DeviceRow,
+    first_seen: String,
+    samples: Vec<SampleRow>,
+    events: Vec<EventRow>,
+}
+
+impl DevicePage {
+    fn newest_first(&self) -> Vec<&SampleRow> {
+        self.samples.iter().rev().collect()
+    }
+}
+
+fn time(at: &DateTime<Utc>) -> String {
+    at.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn html<T: Template>(page: T) -> Response<Body> {
+    match page.render() {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(body))
+            .unwrap_or_default(),
+        Err(err) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(err.to_string()))
+            .unwrap_or_default(),
+    }
+}
+
+pub fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap_or_default()
+}
+
+fn status_view(status: &status::State) -> StatusView {
+    StatusView {
+        last_scan: status
+            .last_scan
+            .as_ref()
+            .map(time)
+            .unwrap_or_else(|| "never".to_string()),
+        scan_error: status.scan_error.clone().unwrap_or_default(),
+        transmit: match (&status.last_transmit, &status.transmit_error) {
+            (None, _) => "nothing sent yet".to_string(),
+            (Some(_), Some(err)) => format!("upload failed: {}", err),
+            (Some(at), None) => format!("uploaded {}", time(at)),
+        },
+    }
+}
+
+fn event_row(event: &TimedEvent) -> EventRow {
+    EventRow {
+        at: time(&event.at),
+        category: match event.event {
+            Event::Device(_) => "device",
+            Event::Security(_) => "security",
+            Event::Certificate(_) => "certificate",
+        },
+        text: event.event.to_string(),
+    }
+}
+
+/// everything known about one device, from the last scan, the history and the tracker
+fn device_row(inventory: &Inventory, tracker: &DeviceTracker, mac: &str) -> DeviceRow {
+    let current = inventory.devices.values().find(|d| d.mac == mac);
+    let tracked = tracker.devices.get(mac);
+    let samples = inventory.history.samples.get(mac);
+    let answered = samples.map_or(0, |s| s.iter().filter(|s| s.ping_ms.is_some()).count());
+
+    DeviceRow {
+        mac: mac.to_string(),
+        ip: current
+            .map(|d| d.local_address.to_string())
+            .or_else(|| tracked.map(|t| t.ip.clone()))
+            .unwrap_or_default(),
+        hostname: current
+            .and_then(|d| d.hostname.clone())
+            .or_else(|| tracked.and_then(|t| t.hostname.clone()))
+            .unwrap_or_default(),
+        vendor: current
+            .and_then(|d| d.vendor.clone())
+            .or_else(|| tracked.and_then(|t| t.vendor.clone()))
+            .unwrap_or_default(),
+        latency: current
+            .map(|d| format!("{} ms", d.ping_ms))
+            .unwrap_or_else(|| "-".to_string()),
+        uptime: match samples {
+            Some(samples) if !samples.is_empty() => {
+                format!("{:.0}%", answered as f64 * 100.0 / samples.len() as f64)
+            }
+            _ => "-".to_string(),
+        },
+        last_seen: tracked
+            .map(|t| time(&t.last_seen))
+            .or_else(|| {
+                samples
+                    .and_then(|s| s.iter().rev().find(|s| s.ping_ms.is_some()))
+                    .map(|s| time(&s.at))
+            })
+            .unwrap_or_default(),
+        up: current.is_some(),
+    }
+}
+
+pub async fn index(context: &Context) -> Response<Body> {
+    let tracker = DeviceTracker::load().unwrap_or_default();
+    let inventory = context.inventory.read().await;
+
+    let macs: BTreeSet<&String> = inventory
+        .devices
+        .values()
+        .map(|d| &d.mac)
+        .chain(inventory.history.samples.keys())
+        .collect();
+
+    let mut rows: Vec<DeviceRow> = macs
+        .into_iter()
+        .map(|mac| device_row(&inventory, &tracker, mac))
+        .collect();
+    rows.sort_by_key(|row| (row.up, row.ip.parse::<IpAddr>().ok()));
+    rows.reverse();
+
+    let up = rows.iter().filter(|row| row.up).count();
+    html(DashboardPage {
+        status: status_view(&inventory.status),
+        down: rows.len() - up,
+        up,
+        rows,
+        events: inventory
+            .history
+            .events
+            .iter()
+            .rev()
+            .map(event_row)
+            .collect(),
+    })
+}
+
+pub async fn device(context: &Context, mac: &str) -> Response<Body> {
+    let mac = mac.replace('-', ":").to_lowercase();
+    let tracker = DeviceTracker::load().unwrap_or_default();
+    let inventory = context.inventory.read().await;
+
+    let known = inventory.devices.values().any(|d| d.mac == mac)
+        || inventory.history.samples.contains_key(&mac)
+        || tracker.devices.contains_key(&mac);
+    if !known {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("unknown device"))
+            .unwrap_or_default();
+    }
+
+    let samples = inventory
+        .history
+        .samples
+        .get(&mac)
+        .map(|samples| {
+            samples
+                .iter()
+                .map(|sample| SampleRow {
+                    at: time(&sample.at),
+                    ip: sample.ip.clone().unwrap_or_default(),
+                    latency: sample
+                        .ping_ms
+                        .map(|ms| format!("{} ms", ms))
+                        .unwrap_or_default(),
+                    up: sample.ping_ms.is_some(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    html(DevicePage {
+        status: status_view(&inventory.status),
+        row: device_row(&inventory, &tracker, &mac),
+        first_seen: tracker
+            .devices
+            .get(&mac)
+            .map(|t| time(&t.first_seen))
+            .unwrap_or_default(),
+        samples,
+        events: inventory
+            .history
+            .events
+            .iter()
+            .rev()
+            .filter(|e| e.event.concerns_mac(&mac))
+            .map(event_row)
+            .collect(),
+    })
+}
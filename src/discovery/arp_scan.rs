@@ -1,13 +1,18 @@
 use super::reverse_dns::DiscoveredHost;
-use libarp::client::ArpClient;
-use std::{net::IpAddr, time::Duration};
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("arp resolution io error: {0}")]
-    Io(#[from] std::io::Error),
-}
+use libarp::{
+    arp::ArpMessage,
+    client::ArpClient,
+    interfaces::{Interface, MacAddr},
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// every MAC address seen claiming an IP address while scanning
+pub type ArpClaims = HashMap<IpAddr, BTreeSet<String>>;
 
 #[derive(Debug, Clone)]
 pub struct DiscoveredHostWithMac {
@@ -15,28 +20,97 @@ pub struct DiscoveredHostWithMac {
     pub mac: String,
 }
 
-pub async fn scan(hosts: Vec<DiscoveredHost>) -> Vec<DiscoveredHostWithMac> {
-    let arps = hosts
-        .into_iter()
-        .map(|h| async move { resolve_simple(h).await });
+pub async fn scan(hosts: Vec<DiscoveredHost>) -> (Vec<DiscoveredHostWithMac>, ArpClaims) {
+    #[cfg(target_os = "linux")]
+    if !allowed() {
+        return from_neighbours(hosts);
+    }
+
+    let claims = Mutex::new(ArpClaims::new());
+
+    let arps = hosts.into_iter().map(|h| resolve_simple(h, &claims));
 
-    futures::future::join_all(arps)
+    let hosts = futures::future::join_all(arps)
         .await
         .into_iter()
-        .filter_map(std::convert::identity)
-        .collect()
+        .flatten()
+        .collect();
+
+    (hosts, claims.into_inner().unwrap_or_default())
+}
+
+/// whether we can send arp requests ourselves, which needs raw packet sockets
+#[cfg(target_os = "linux")]
+pub fn allowed() -> bool {
+    socket2::Socket::new(socket2::Domain::PACKET, socket2::Type::RAW, None).is_ok()
+}
+
+/// without raw sockets, use the macs the kernel learned while we pinged everyone
+#[cfg(target_os = "linux")]
+fn from_neighbours(hosts: Vec<DiscoveredHost>) -> (Vec<DiscoveredHostWithMac>, ArpClaims) {
+    let claims = std::fs::read_to_string("/proc/net/arp")
+        .map(|table| parse_neighbours(&table))
+        .unwrap_or_default();
+
+    let hosts = hosts
+        .into_iter()
+        .filter_map(|host| {
+            let mac = claims.get(&host.ip)?.iter().next()?.clone();
+            Some(DiscoveredHostWithMac { host, mac })
+        })
+        .collect();
+
+    (hosts, claims)
+}
+
+/// parse the kernel's `/proc/net/arp` table, skipping incomplete entries
+#[cfg(target_os = "linux")]
+fn parse_neighbours(table: &str) -> ArpClaims {
+    let mut claims = ArpClaims::new();
+    for line in table.lines().skip(1) {
+        // IP address, HW type, Flags, HW address, Mask, Device
+        if let [ip, _, flags, mac, ..] = line.split_whitespace().collect::<Vec<_>>()[..] {
+            if let (Ok(ip), false) = (ip.parse(), flags == "0x0") {
+                claims.entry(ip).or_default().insert(mac.to_lowercase());
+            }
+        }
+    }
+    claims
 }
 
-async fn resolve_simple(host: DiscoveredHost) -> Option<DiscoveredHostWithMac> {
-    let mut client = ArpClient::new();
+async fn resolve_simple(
+    host: DiscoveredHost,
+    claims: &Mutex<ArpClaims>,
+) -> Option<DiscoveredHostWithMac> {
+    let iface = Interface::new();
+    let mut client = ArpClient::new_with_iface(&iface);
 
     let ip = match host.ip {
         IpAddr::V4(v4) => v4,
         _ => return None,
     };
 
-    let mac = client
-        .ip_to_mac(ip, Some(Duration::from_secs(2)))
+    let request = ArpMessage::new_arp_request(iface.get_mac(), iface.get_ip()?, ip);
+
+    // record every ip/mac pair that goes by while waiting for our answer,
+    // a second device answering for the same ip shows up here
+    let mac: MacAddr = client
+        .send_message_with_check(Some(Duration::from_secs(2)), request, &|message| {
+            if message.source_protocol_address != Ipv4Addr::UNSPECIFIED {
+                if let Ok(mut claims) = claims.lock() {
+                    claims
+                        .entry(IpAddr::V4(message.source_protocol_address))
+                        .or_default()
+                        .insert(message.source_hardware_address.to_string());
+                }
+            }
+
+            if message.source_protocol_address == ip {
+                Some(message.source_hardware_address)
+            } else {
+                None
+            }
+        })
         .await
         .ok()?;
 
@@ -45,3 +119,22 @@ async fn resolve_simple(host: DiscoveredHost) -> Option<DiscoveredHostWithMac> {
         mac: mac.to_string(),
     })
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_neighbours() {
+        let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         60:12:8b:8f:38:ac     *        eth0
+192.168.1.23     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.40     0x1         0x2         B8:27:EB:01:02:03     *        eth0
+";
+        let claims = parse_neighbours(table);
+        assert_eq!(claims.len(), 2);
+        assert!(claims[&"192.168.1.40".parse::<IpAddr>().unwrap()].contains("b8:27:eb:01:02:03"));
+        assert!(!claims.contains_key(&"192.168.1.23".parse::<IpAddr>().unwrap()));
+    }
+}
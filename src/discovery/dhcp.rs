@@ -0,0 +1,214 @@
+use super::Iface;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("dhcp socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid interface mac address: {0}")]
+    Mac(String),
+}
+
+/// A DHCPOFFER received in response to our discover
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhcpOffer {
+    /// the server identifier option, or the packet source if it was missing
+    pub server: Ipv4Addr,
+    pub offered_ip: Ipv4Addr,
+    pub relay: Option<Ipv4Addr>,
+}
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+
+/// Broadcast a DHCPDISCOVER on the interface and collect every offer that comes back within `window`
+pub async fn discover_servers(iface: &Iface, window: Duration) -> Result<Vec<DhcpOffer>, Error> {
+    let mac = parse_mac(&iface.mac).ok_or_else(|| Error::Mac(iface.mac.clone()))?;
+    let xid: u32 = rand::random();
+
+    let socket = client_socket(iface)?;
+    let discover = discover_packet(xid, mac);
+    socket
+        .send_to(
+            &discover,
+            SocketAddr::from((Ipv4Addr::BROADCAST, SERVER_PORT)),
+        )
+        .await?;
+
+    let mut offers: Vec<DhcpOffer> = vec![];
+    let mut buf = [0u8; 1500];
+    let deadline = Instant::now() + window;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let (len, from) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) => break,
+        };
+
+        let source = match from {
+            SocketAddr::V4(v4) => *v4.ip(),
+            _ => continue,
+        };
+
+        if let Some(offer) = parse_offer(&buf[..len], xid, source) {
+            if !offers.contains(&offer) {
+                offers.push(offer);
+            }
+        }
+    }
+
+    Ok(offers)
+}
+
+/// a broadcast socket on the dhcp client port that can share it with a running dhcp client
+fn client_socket(iface: &Iface) -> Result<UdpSocket, Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+
+    #[cfg(target_os = "linux")]
+    socket.bind_device(Some(iface.name.as_bytes()))?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = iface;
+
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT);
+    socket.bind(&SockAddr::from(addr))?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes = mac
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut out = [0u8; 6];
+    if bytes.len() != out.len() {
+        return None;
+    }
+    out.copy_from_slice(&bytes);
+    Some(out)
+}
+
+fn discover_packet(xid: u32, mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0u8; 236];
+    packet[0] = 1; // op: BOOTREQUEST
+    packet[1] = 1; // htype: ethernet
+    packet[2] = 6; // hlen
+    packet[4..8].copy_from_slice(&xid.to_be_bytes());
+    packet[10..12].copy_from_slice(&0x8000u16.to_be_bytes()); // ask for a broadcast reply
+    packet[28..34].copy_from_slice(&mac);
+
+    packet.extend_from_slice(&MAGIC_COOKIE);
+    packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, DHCPDISCOVER]);
+    // subnet mask, router, dns
+    packet.extend_from_slice(&[OPTION_PARAMETER_LIST, 3, 1, 3, 6]);
+    packet.push(OPTION_END);
+
+    // some servers ignore requests shorter than a minimal BOOTP packet
+    packet.resize(300, 0);
+    packet
+}
+
+fn parse_offer(packet: &[u8], xid: u32, source: Ipv4Addr) -> Option<DhcpOffer> {
+    if packet.len() < 240 || packet[0] != 2 || packet[4..8] != xid.to_be_bytes() {
+        return None;
+    }
+    if packet[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let ip_at = |offset: usize| {
+        Ipv4Addr::new(
+            packet[offset],
+            packet[offset + 1],
+            packet[offset + 2],
+            packet[offset + 3],
+        )
+    };
+
+    let mut message_type = None;
+    let mut server = None;
+
+    let mut options = &packet[240..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_END => break,
+            0 => {
+                options = rest;
+                continue;
+            }
+            _ => {}
+        }
+
+        let (len, rest) = rest.split_first()?;
+        let value = rest.get(..*len as usize)?;
+        match (*code, value) {
+            (OPTION_MESSAGE_TYPE, [kind]) => message_type = Some(*kind),
+            (OPTION_SERVER_ID, [a, b, c, d]) => server = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => {}
+        }
+        options = &rest[*len as usize..];
+    }
+
+    if message_type != Some(DHCPOFFER) {
+        return None;
+    }
+
+    let relay = Some(ip_at(24)).filter(|ip| !ip.is_unspecified());
+
+    Some(DhcpOffer {
+        server: server.unwrap_or(source),
+        offered_ip: ip_at(16),
+        relay,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_offer() {
+        let xid = 0xdeadbeef;
+        let mut packet = discover_packet(xid, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        packet[0] = 2;
+        packet[16..20].copy_from_slice(&[192, 168, 1, 50]);
+        packet.truncate(240);
+        packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, DHCPOFFER]);
+        packet.extend_from_slice(&[OPTION_SERVER_ID, 4, 192, 168, 1, 1]);
+        packet.push(OPTION_END);
+
+        let source = Ipv4Addr::new(192, 168, 1, 254);
+        assert_eq!(
+            parse_offer(&packet, xid, source),
+            Some(DhcpOffer {
+                server: Ipv4Addr::new(192, 168, 1, 1),
+                offered_ip: Ipv4Addr::new(192, 168, 1, 50),
+                relay: None,
+            })
+        );
+        assert_eq!(parse_offer(&packet, xid + 1, source), None);
+        assert_eq!(
+            parse_mac("aa:bb:cc:dd:ee:ff"),
+            Some([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])
+        );
+    }
+}
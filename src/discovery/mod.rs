@@ -1,8 +1,10 @@
-mod arp_scan;
-mod ping;
-mod reverse_dns;
-mod ssdp;
+pub mod arp_scan;
+pub mod dhcp;
+pub mod ping;
+pub mod reverse_dns;
+pub mod ssdp;
 
+pub use arp_scan::ArpClaims;
 use reverse_dns::reverse_dns;
 use std::{
     collections::HashMap,
@@ -12,10 +14,13 @@ use std::{
 };
 use tokio::net::UdpSocket;
 
+use crate::shutdown::Shutdown;
+use ipnetwork::IpNetwork;
+
 /// an attempt at a uniquely identifiable name for the device
 pub type DeviceName = String;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct DiscoveredDevice {
     pub local_address: IpAddr,
     pub ping_ms: u128,
@@ -30,48 +35,95 @@ impl Display for DiscoveredDevice {
         let data = format!(
             "{} - {} - {} - {} - {} ({}ms)",
             self.mac,
-            self.vendor.as_ref().map(|s| s.as_str()).unwrap_or("?"),
+            self.vendor.as_deref().unwrap_or("?"),
             &self.local_address,
-            self.hostname.as_ref().map(|s| s.as_str()).unwrap_or("?"),
-            self.meta.as_ref().map(|s| s.as_str()).unwrap_or("?"),
+            self.hostname.as_deref().unwrap_or("?"),
+            self.meta.as_deref().unwrap_or("?"),
             self.ping_ms
         );
         f.write_str(data.as_str())
     }
 }
 
+/// Everything learned about the local network in one scan
+#[derive(Debug, Clone, Default)]
+pub struct Discovery {
+    pub devices: HashMap<DeviceName, DiscoveredDevice>,
+    pub arp_claims: ArpClaims,
+    pub gateway: Option<IpAddr>,
+    /// the interfaces the scanned networks are on
+    pub interfaces: Vec<Iface>,
+    /// shutdown cut the scan short, devices and names may be missing
+    pub partial: bool,
+}
+
+/// Scan `subnets`, or the LAN interface's subnet without any, stops launching
+/// probes once `shutdown` is requested and returns what it found so far
 pub async fn discover_devices(
-) -> Result<HashMap<DeviceName, DiscoveredDevice>, Box<dyn std::error::Error>> {
+    subnets: &[IpNetwork],
+    shutdown: &Shutdown,
+) -> Result<Discovery, Box<dyn std::error::Error>> {
     // 0. discover the network settings: our IP + netmask
-    let network_iface = get_network_interface_ip_with_masks().await?;
+    let network_iface = network_interface().await?;
+    let networks = if subnets.is_empty() {
+        vec![IpNetwork::with_netmask(
+            network_iface.ip,
+            network_iface.mask,
+        )?]
+    } else {
+        subnets.to_vec()
+    };
+    // subnets off the LAN interface are probed for rogue dhcp servers on their own interface
+    let interfaces = match interfaces_for(&networks) {
+        Ok(found) if !subnets.is_empty() && !found.is_empty() => found,
+        _ => vec![network_iface],
+    };
 
     // 1. ping entire range
-    let results = ping::ping_subnet(network_iface.ip, network_iface.mask).await?;
-    // dbg!(&results);
+    let mut results = vec![];
+    for network in networks {
+        results.extend(ping::ping_subnet(network, shutdown).await);
+    }
+    results.sort_by_key(|ping| ping.ip);
+    results.dedup_by_key(|ping| ping.ip);
 
     // 2. dns reverse lookup
-    let results = reverse_dns(results).await?;
-    // dbg!(&results);
+    let results = if shutdown.is_requested() {
+        results
+            .into_iter()
+            .map(|ping| reverse_dns::DiscoveredHost {
+                ip: ping.ip,
+                ping_duration: ping.duration,
+                hostname: None,
+                meta: vec![],
+            })
+            .collect()
+    } else {
+        reverse_dns(results).await?
+    };
 
-    // 3. arp scan (get mac addresses)
-    let results = arp_scan::scan(results).await;
-    // dbg!(&results);
+    // 3. arp scan (get mac addresses), devices are named by mac so this always runs
+    let (results, arp_claims) = arp_scan::scan(results).await;
 
     // 4. check upnp devices with ssdp
-    let services = ssdp::discover_services().await.ok().unwrap_or_default();
+    let services = if shutdown.is_requested() {
+        HashMap::new()
+    } else {
+        ssdp::discover_services().await.ok().unwrap_or_default()
+    };
 
     let discovered = results.into_iter().map(|mut device| {
         let name = device.mac.clone();
 
         let service = services.get(&device.host.ip);
-        if let Some(model) = service.map(|s| s.model_name.clone()).flatten() {
+        if let Some(model) = service.and_then(|s| s.model_name.clone()) {
             device.host.meta.push(model);
         }
 
         let hostname = device
             .host
             .hostname
-            .or(service.map(|s| s.friendly_name.clone()).flatten());
+            .or_else(|| service.and_then(|s| s.friendly_name.clone()));
 
         let vendor = reverseping::get_vendor_for_mac(&device.mac);
 
@@ -85,7 +137,7 @@ pub async fn discover_devices(
             name,
             DiscoveredDevice {
                 local_address: device.host.ip,
-                hostname: hostname,
+                hostname,
                 mac: device.mac,
                 ping_ms: device.host.ping_duration.as_millis(),
                 vendor,
@@ -94,39 +146,112 @@ pub async fn discover_devices(
         )
     });
 
-    Ok(HashMap::from_iter(discovered))
+    Ok(Discovery {
+        devices: HashMap::from_iter(discovered),
+        arp_claims,
+        gateway: default_gateway(),
+        interfaces,
+        partial: shutdown.is_requested(),
+    })
+}
+
+/// the IPv4 default gateway, read from the kernel routing table
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+
+    // Iface Destination Gateway Flags ... with addresses in native byte order hex
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16)
+                .ok()
+                .filter(|gw| *gw != 0)
+                .map(|gw| IpAddr::V4(Ipv4Addr::from(gw.to_ne_bytes()))),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub fn default_gateway() -> Option<IpAddr> {
+    let output = std::process::Command::new("route")
+        .args(&["-n", "get", "default"])
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .and_then(|gw| gw.trim().parse().ok())
+}
+
+#[cfg(target_os = "windows")]
+pub fn default_gateway() -> Option<IpAddr> {
+    None
 }
 
 #[derive(Debug, Clone)]
-struct Iface {
-    name: String,
-    ip: IpAddr,
-    mask: IpAddr,
+pub struct Iface {
+    pub name: String,
+    pub mac: String,
+    pub ip: IpAddr,
+    pub mask: IpAddr,
 }
 
-async fn get_network_interface_ip_with_masks() -> Result<Iface, Box<dyn std::error::Error>> {
+/// the interface holding the address we'd use to reach the internet
+pub async fn network_interface() -> Result<Iface, Box<dyn std::error::Error>> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.connect("8.8.8.8:80").await?;
     let local_addr = socket.local_addr()?.ip();
 
+    let result = addresses()?
+        .into_iter()
+        .find(|iface| iface.ip == local_addr)
+        .ok_or("No network interface found")?;
+
+    Ok(result)
+}
+
+/// the IPv4 interfaces attached to any of `networks`, each once
+pub fn interfaces_for(networks: &[IpNetwork]) -> Result<Vec<Iface>, Box<dyn std::error::Error>> {
+    let mut found: Vec<Iface> = vec![];
+    for iface in addresses()? {
+        let attached = match IpNetwork::with_netmask(iface.ip, iface.mask) {
+            Ok(own) if iface.ip.is_ipv4() && !iface.ip.is_loopback() => networks
+                .iter()
+                .any(|network| network.contains(iface.ip) || own.contains(network.ip())),
+            _ => false,
+        };
+        if attached && !found.iter().any(|known| known.name == iface.name) {
+            found.push(iface);
+        }
+    }
+    Ok(found)
+}
+
+/// every address on every interface, with its netmask
+fn addresses() -> Result<Vec<Iface>, Box<dyn std::error::Error>> {
     let ifaces =
         ifcfg::IfCfg::get().map_err(|e| format!("error getting network config: {:?}", e))?;
 
-    let result = ifaces
+    Ok(ifaces
         .into_iter()
-        .map(|f| {
+        .flat_map(|f| {
             let name = f.name.clone();
+            let mac = f.mac.clone();
             f.addresses
                 .into_iter()
-                .filter(|addr| addr.address.map(|a| a.ip()) == Some(local_addr))
                 .map(|addr| match (addr.address, addr.mask) {
                     (Some(ip), Some(mask)) => Some(Iface {
                         name: name.clone(),
+                        mac: mac.clone(),
                         ip: ip.ip(),
                         mask: mask.ip(),
                     }),
                     (Some(ip), None) => Some(Iface {
                         name: name.clone(),
+                        mac: mac.clone(),
                         ip: ip.ip(),
                         mask: if ip.is_ipv4() {
                             IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0))
@@ -139,9 +264,5 @@ async fn get_network_interface_ip_with_masks() -> Result<Iface, Box<dyn std::err
                 .collect::<Vec<Option<Iface>>>()
         })
         .flatten()
-        .filter_map(std::convert::identity)
-        .next()
-        .ok_or("No network interface found")?;
-
-    Ok(result)
+        .collect())
 }
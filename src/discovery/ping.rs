@@ -1,14 +1,11 @@
 use futures::future;
-use ipnetwork::IpNetworkError;
+use ipnetwork::IpNetwork;
 use itertools::Itertools;
+#[cfg(not(windows))]
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{net::IpAddr, time::Duration};
-use thiserror::Error;
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("invalid ip address found")]
-    IpError(#[from] IpNetworkError),
-}
+use crate::shutdown::Shutdown;
 
 #[derive(Debug)]
 pub struct PingResult {
@@ -16,24 +13,39 @@ pub struct PingResult {
     pub duration: Duration,
 }
 
-pub async fn ping_subnet(ip: IpAddr, mask: IpAddr) -> Result<Vec<PingResult>, Error> {
-    let network = ipnetwork::IpNetwork::with_netmask(ip, mask)?;
-    dbg!(network);
+/// addresses pinged at once
+const BATCH: usize = 200;
+/// how long to wait for an echo reply
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The longest `ping_subnet` takes, when nothing answers
+pub fn sweep_time(network: IpNetwork) -> Duration {
+    let addresses = match network {
+        IpNetwork::V4(network) => network.size() as u64,
+        IpNetwork::V6(_) => u64::MAX,
+    };
+    TIMEOUT.saturating_mul(addresses.div_ceil(BATCH as u64).min(u32::MAX as u64) as u32)
+}
 
+/// Ping every address in the subnet a batch at a time, no new batch starts after shutdown
+pub async fn ping_subnet(network: IpNetwork, shutdown: &Shutdown) -> Vec<PingResult> {
     let chunked = network
         .into_iter()
-        .chunks(200)
+        .chunks(BATCH)
         .into_iter()
         .map(|chunk| chunk.collect_vec())
         .collect::<Vec<Vec<IpAddr>>>();
 
     let mut results = vec![];
     for chunk in chunked {
+        if shutdown.is_requested() {
+            break;
+        }
         let next = ping_ips(chunk).await;
         results.extend(next);
     }
 
-    Ok(results)
+    results
 }
 
 async fn ping_ips(ips: Vec<IpAddr>) -> Vec<PingResult> {
@@ -48,15 +60,97 @@ async fn ping_ips(ips: Vec<IpAddr>) -> Vec<PingResult> {
 }
 
 #[cfg(not(windows))]
-async fn ping(ip: IpAddr) -> Result<(IpAddr, Duration), Box<dyn std::error::Error>> {
+lazy_static::lazy_static! {
+    /// raw sockets need root or CAP_NET_RAW
+    static ref RAW_ICMP: bool =
+        Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).is_ok();
+}
+
+/// whether this process can send ICMP echo requests at all
+#[cfg(not(windows))]
+pub fn allowed() -> bool {
+    *RAW_ICMP || unprivileged_allowed()
+}
+
+/// unprivileged ICMP sockets, allowed for the groups in `net.ipv4.ping_group_range`
+#[cfg(target_os = "linux")]
+pub fn unprivileged_allowed() -> bool {
+    Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)).is_ok()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn unprivileged_allowed() -> bool {
+    false
+}
+
+#[cfg(not(windows))]
+pub async fn ping(ip: IpAddr) -> Result<(IpAddr, Duration), Box<dyn std::error::Error>> {
+    #[cfg(target_os = "linux")]
+    if !*RAW_ICMP {
+        let duration = echo(ip, TIMEOUT).await?;
+        return Ok((ip, duration));
+    }
+
     let mut pinger = surge_ping::Pinger::new(ip)?;
-    pinger.timeout(Duration::from_secs(2));
+    pinger.timeout(TIMEOUT);
     let (_, duration) = pinger.ping(0).await?;
     Ok((ip, duration))
 }
 
+/// send one echo request over an unprivileged datagram socket
+#[cfg(target_os = "linux")]
+async fn echo(ip: IpAddr, timeout: Duration) -> std::io::Result<Duration> {
+    let (domain, protocol, request, reply) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4, 8, 0),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6, 128, 129),
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    socket.connect(&std::net::SocketAddr::new(ip, 0).into())?;
+    let socket = tokio::net::UdpSocket::from_std(std::net::UdpSocket::from(socket))?;
+
+    // the kernel sets the identifier and only hands us replies to it, so matching
+    // on the sequence number is enough
+    let seq: u16 = rand::random();
+    let mut packet = vec![request, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(b"reverseping");
+    let sum = checksum(&packet);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let start = std::time::Instant::now();
+    socket.send(&packet).await?;
+
+    let wait = async {
+        let mut buf = [0u8; 128];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if len >= 8 && buf[0] == reply && buf[6..8] == seq.to_be_bytes() {
+                return Ok(start.elapsed());
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "ping timed out"))?
+}
+
+#[cfg(target_os = "linux")]
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 #[cfg(windows)]
-async fn ping(ip: IpAddr) -> Result<(IpAddr, Duration), Box<dyn std::error::Error>> {
+pub async fn ping(ip: IpAddr) -> Result<(IpAddr, Duration), Box<dyn std::error::Error>> {
     let mut pinger = winping::AsyncPinger::new();
     pinger.set_timeout(2);
     let buf = winping::Buffer::with_data(vec![0]);
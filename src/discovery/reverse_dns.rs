@@ -1,6 +1,6 @@
 use super::ping::PingResult;
 use itertools::Itertools;
-use std::{net::IpAddr, time::Duration, vec};
+use std::{net::IpAddr, time::Duration};
 use thiserror::Error;
 use trust_dns_resolver::error::ResolveError;
 
@@ -23,7 +23,7 @@ use trust_dns_client::{
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("dns resolution error")]
-    Dns(#[from] ResolveError),
+    Dns(#[from] Box<ResolveError>),
     #[error("dns proto error")]
     DnsProto(#[from] ProtoError),
     #[error("dns client error")]
@@ -49,13 +49,22 @@ pub async fn reverse_dns(pings: Vec<PingResult>) -> Result<Vec<DiscoveredHost>,
         .collect()
 }
 
-#[derive(Debug)]
-struct ResolvedHost {
-    hostname: Option<String>,
-    meta: Vec<String>,
+#[derive(Debug, Default)]
+pub struct ResolvedHost {
+    pub hostname: Option<String>,
+    pub meta: Vec<String>,
 }
 
 async fn resolve2(ip: IpAddr) -> Result<ResolvedHost, Error> {
+    // most hosts don't run an mdns responder and never answer
+    match query_host(ip).await {
+        Err(Error::DnsClient(_)) => Ok(ResolvedHost::default()),
+        result => result,
+    }
+}
+
+/// Ask the host's mdns responder directly for its name
+pub async fn query_host(ip: IpAddr) -> Result<ResolvedHost, Error> {
     let stream = UdpClientStream::<UdpSocket>::new((ip, 5353).into());
     let client = AsyncClient::connect(stream);
 
@@ -65,39 +74,30 @@ async fn resolve2(ip: IpAddr) -> Result<ResolvedHost, Error> {
 
     // Create a query future
     let arpa_name = arpa_name(ip);
-    let result = client
+    let mut resp = client
         .query(Name::from_str(&arpa_name)?, DNSClass::ANY, RecordType::ANY)
-        .await;
-    dbg!(&result);
-
-    let mut resp = match result {
-        Ok(r) => r,
-        Err(_) => {
-            return Ok(ResolvedHost {
-                hostname: None,
-                meta: vec![],
-            })
-        }
-    };
+        .await?;
 
     // get first PTR hostname
-    let mut hostname = None;
-    let mut responses = resp.take_answers().into_iter();
-    while let Some(RData::PTR(name)) = responses.next().map(|r| r.rdata().clone()) {
-        hostname = Some(name.to_string());
-        break;
-    }
+    let hostname = match resp
+        .take_answers()
+        .into_iter()
+        .next()
+        .map(|r| r.rdata().clone())
+    {
+        Some(RData::PTR(name)) => Some(name.to_string()),
+        _ => None,
+    };
 
     // collect txt info
     let meta = resp
         .additionals()
         .iter()
         .map(|r| r.rdata())
-        .map(|r| match r {
+        .filter_map(|r| match r {
             RData::TXT(val) => Some(val.to_string()),
             _ => None,
         })
-        .filter_map(std::convert::identity)
         .collect();
 
     Ok(ResolvedHost { hostname, meta })
@@ -180,7 +180,6 @@ mod tests {
 
     use trust_dns_resolver::{
         config::{NameServerConfig, ResolverConfig, ResolverOpts},
-        dns_sd::DnsSdHandle,
         TokioAsyncResolver, TokioHandle,
     };
 
@@ -261,8 +260,7 @@ mod tests {
 
         let resolver = TokioAsyncResolver::new(config, opts, TokioHandle).unwrap();
 
-        const ALL: &'static str = "_services._dns-sd._udp.local";
-        const HTTP: &'static str = "_http._tcp.local";
+        const ALL: &str = "_services._dns-sd._udp.local";
         let name = Name::from_str(ALL).unwrap();
 
         let res = resolver
@@ -286,12 +284,9 @@ mod tests {
         std::env::set_var("RUST_LOG", "debug");
         pretty_env_logger::init();
 
-        const ALL: &'static str = "_services._dns-sd._udp.local";
-        const HTTP: &'static str = "_http._tcp.local";
-        const AD: &'static str = "_airdrop._tcp.local";
+        const AD: &str = "_airdrop._tcp.local";
 
         let ip: IpAddr = "224.0.0.251".parse().unwrap();
-        let r = super::resolve3(ip, AD).await.unwrap();
-        dbg!(r);
+        super::resolve3(ip, AD).await.unwrap();
     }
 }
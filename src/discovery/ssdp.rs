@@ -10,6 +10,8 @@ pub struct Service {
     pub friendly_name: Option<String>,
     pub model_name: Option<String>,
     pub vendor: Option<String>,
+    /// why the device description couldn't be read
+    pub error: Option<String>,
 }
 
 pub async fn discover_services() -> Result<HashMap<IpAddr, Service>, Box<dyn std::error::Error>> {
@@ -27,12 +29,7 @@ pub async fn discover_services() -> Result<HashMap<IpAddr, Service>, Box<dyn std
             _ => continue,
         };
 
-        let ip = match url
-            .host_str()
-            .map(IpAddr::from_str)
-            .map(Result::ok)
-            .flatten()
-        {
+        let ip = match url.host_str().and_then(|host| IpAddr::from_str(host).ok()) {
             Some(ip) => ip,
             _ => continue,
         };
@@ -41,12 +38,9 @@ pub async fn discover_services() -> Result<HashMap<IpAddr, Service>, Box<dyn std
             continue;
         }
 
-        let device = match get_service_description(&client, search.location()).await {
-            Ok(dev) => dev.device,
-            Err(e) => {
-                dbg!(e);
-                continue;
-            }
+        let (device, error) = match get_service_description(&client, search.location()).await {
+            Ok(dev) => (Some(dev.device), None),
+            Err(e) => (None, Some(e.to_string())),
         };
 
         services.insert(
@@ -54,9 +48,10 @@ pub async fn discover_services() -> Result<HashMap<IpAddr, Service>, Box<dyn std
             Service {
                 location: search.location().to_string(),
                 ip,
-                friendly_name: device.friendly_name,
-                model_name: device.model_name,
-                vendor: device.manufacturer,
+                friendly_name: device.as_ref().and_then(|d| d.friendly_name.clone()),
+                model_name: device.as_ref().and_then(|d| d.model_name.clone()),
+                vendor: device.and_then(|d| d.manufacturer),
+                error,
             },
         );
     }
@@ -0,0 +1,511 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reverseping::AgentCredential;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    agent::{self, Agent, AgentConfig},
+    config, credential, discovery, transmit,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one environment check and what to do about it
+#[derive(Debug)]
+struct Finding {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    fix: Option<String>,
+}
+
+impl Finding {
+    fn ok<S: Into<String>>(name: &'static str, detail: S) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn<S: Into<String>, F: Into<String>>(name: &'static str, detail: S, fix: F) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn fail<S: Into<String>, F: Into<String>>(name: &'static str, detail: S, fix: F) -> Self {
+        Self {
+            status: Status::Fail,
+            ..Self::warn(name, detail, fix)
+        }
+    }
+}
+
+const MDNS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+const SSDP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// Check everything the agent needs from its environment, hand `out` the findings
+/// line by line and return whether nothing failed
+pub async fn run(
+    loaded: Result<config::Effective, config::Error>,
+    out: &mut dyn FnMut(String),
+) -> bool {
+    let conf = match &loaded {
+        Ok(effective) => effective.config.clone(),
+        Err(_) => AgentConfig::default(),
+    };
+
+    let iface = discovery::network_interface().await;
+
+    let mut findings = vec![icmp()];
+    #[cfg(target_os = "linux")]
+    findings.push(arp());
+    findings.push(match &iface {
+        Ok(iface) => Finding::ok(
+            "interface",
+            format!(
+                "{} {}/{} ({}), gateway {}",
+                iface.name,
+                iface.ip,
+                iface.mask,
+                iface.mac,
+                discovery::default_gateway()
+                    .map(|gw| gw.to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            ),
+        ),
+        Err(err) => Finding::fail(
+            "interface",
+            format!("could not detect the LAN interface: {}", err),
+            "the interface is picked from the route to 8.8.8.8, make sure there is a default route \
+             (`ip route`) and the network is up",
+        ),
+    });
+
+    let iface_ip = match &iface {
+        Ok(discovery::Iface {
+            ip: std::net::IpAddr::V4(ip),
+            ..
+        }) => *ip,
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+    findings.push(multicast("mdns", MDNS, iface_ip));
+    findings.push(multicast("ssdp", SSDP, iface_ip));
+    findings.push(config(loaded));
+    findings.push(credential());
+    #[cfg(target_os = "linux")]
+    findings.push(systemd());
+    findings.push(api(&conf).await);
+
+    for finding in &findings {
+        let status = match finding.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        out(format!(
+            "[{:>4}] {:<10} {}",
+            status, finding.name, finding.detail
+        ));
+        if let Some(fix) = &finding.fix {
+            out(format!("       {:<10} fix: {}", "", fix));
+        }
+    }
+
+    !findings.iter().any(|f| f.status == Status::Fail)
+}
+
+fn icmp() -> Finding {
+    icmp_finding(
+        Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).is_ok(),
+        discovery::ping::unprivileged_allowed(),
+        &privileges(),
+        &current_exe(),
+    )
+}
+
+fn icmp_finding(raw: bool, unprivileged: bool, privileges: &str, exe: &str) -> Finding {
+    let name = "icmp";
+    if raw {
+        return Finding::ok(name, format!("raw ICMP sockets allowed ({})", privileges));
+    }
+
+    if unprivileged {
+        return Finding::ok(
+            name,
+            format!("unprivileged ICMP sockets allowed ({})", privileges),
+        );
+    }
+
+    Finding::fail(
+        name,
+        format!("no ICMP sockets allowed ({})", privileges),
+        format!(
+            "allow unprivileged ping with `sudo sysctl -w net.ipv4.ping_group_range=\"0 2147483647\"`, \
+             grant the capability with `sudo setcap cap_net_raw+ep {}` or run as root",
+            exe
+        ),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn arp() -> Finding {
+    arp_finding(discovery::arp_scan::allowed(), &current_exe())
+}
+
+#[cfg(target_os = "linux")]
+fn arp_finding(allowed: bool, exe: &str) -> Finding {
+    if allowed {
+        return Finding::ok("arp", "raw packet sockets allowed");
+    }
+
+    Finding::warn(
+        "arp",
+        "no raw packet sockets, macs come from the kernel neighbour table and duplicate ip \
+         detection is limited",
+        format!(
+            "grant the capability with `sudo setcap cap_net_raw+ep {}`, the installed service \
+             gets it through AmbientCapabilities",
+            exe
+        ),
+    )
+}
+
+/// how the current process is privileged, as far as we can tell
+#[cfg(target_os = "linux")]
+fn privileges() -> String {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(str::trim)
+            .unwrap_or("")
+            .to_string()
+    };
+
+    const CAP_NET_RAW: u64 = 1 << 13;
+    let euid = field("Uid:").split_whitespace().nth(1).map(str::to_string);
+    let caps = u64::from_str_radix(&field("CapEff:"), 16).unwrap_or(0);
+    let ping_range = std::fs::read_to_string("/proc/sys/net/ipv4/ping_group_range")
+        .map(|range| range.split_whitespace().collect::<Vec<_>>().join("-"))
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    format!(
+        "root: {}, CAP_NET_RAW: {}, ping_group_range: {}",
+        if euid.as_deref() == Some("0") {
+            "yes"
+        } else {
+            "no"
+        },
+        if caps & CAP_NET_RAW != 0 { "yes" } else { "no" },
+        ping_range
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn privileges() -> String {
+    format!("user {}", whoami::username())
+}
+
+fn current_exe() -> String {
+    std::env::current_exe()
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "reverseping".to_string())
+}
+
+fn multicast(name: &'static str, group: SocketAddrV4, iface: Ipv4Addr) -> Finding {
+    let joined = (|| -> std::io::Result<()> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &iface)
+    })();
+
+    match joined {
+        Ok(()) => Finding::ok(name, format!("joined {}", group)),
+        Err(err) => Finding::warn(
+            name,
+            format!("can't join {}: {}", group, err),
+            format!(
+                "make sure the interface has multicast enabled and udp port {} isn't blocked or held \
+                 exclusively by another process, hostnames and upnp details will be missing otherwise",
+                group.port()
+            ),
+        ),
+    }
+}
+
+fn config(loaded: Result<config::Effective, config::Error>) -> Finding {
+    let path = Agent::config_file()
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_default();
+    config_finding(loaded.and_then(|effective| effective.agent()), &path)
+}
+
+fn config_finding(agent: Result<AgentConfig, config::Error>, path: &str) -> Finding {
+    match agent {
+        Ok(conf) => Finding::ok("config", format!("config is valid, agent {}", conf.agent)),
+        Err(config::Error::Agent(agent::Error::AgentNotConfigured)) => Finding::warn(
+            "config",
+            format!("no agent configured in {} or the environment", path),
+            "run `reverseping enroll <token>` with a token from the dashboard",
+        ),
+        Err(err) => Finding::fail(
+            "config",
+            format!("config is invalid: {}", err),
+            "fix the reported field, or remove the file and run `reverseping up <agent id>` again",
+        ),
+    }
+}
+
+fn credential() -> Finding {
+    let path = credential::path().unwrap_or_default();
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::metadata(&path)
+            .map(|meta| meta.permissions().mode() & 0o777)
+            .unwrap_or(0)
+    };
+    #[cfg(not(unix))]
+    let mode = 0o600;
+
+    credential_finding(credential::load(), &path, mode, chrono::Utc::now())
+}
+
+/// `mode` is the credential file's permission bits, only checked on unix
+fn credential_finding(
+    loaded: Result<Option<AgentCredential>, credential::Error>,
+    path: &Path,
+    mode: u32,
+    now: DateTime<Utc>,
+) -> Finding {
+    let stored = match loaded {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            return Finding::warn(
+                "credential",
+                "not enrolled, reports are only identified by the agent id",
+                "run `reverseping enroll <token>` with a token from the dashboard",
+            )
+        }
+        Err(err) => {
+            return Finding::fail(
+                "credential",
+                err.to_string(),
+                "remove the file and run `reverseping enroll <token>` again",
+            )
+        }
+    };
+
+    if mode & 0o077 != 0 {
+        return Finding::fail(
+            "credential",
+            format!("{} is readable by other users ({:o})", path.display(), mode),
+            format!(
+                "run `chmod 600 {}` and rotate it with `reverseping credential rotate`",
+                path.display()
+            ),
+        );
+    }
+
+    match stored.expires_at {
+        Some(expires) if expires < now => Finding::fail(
+            "credential",
+            format!("the credential expired at {}", expires.to_rfc3339()),
+            "run `reverseping enroll <token>` with a new token",
+        ),
+        Some(expires) => Finding::ok(
+            "credential",
+            format!(
+                "agent {}, expires {}",
+                stored.agent_id,
+                expires.to_rfc3339()
+            ),
+        ),
+        None => Finding::ok("credential", format!("agent {}", stored.agent_id)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn systemd() -> Finding {
+    let service = Agent::service_name();
+    let (enabled, active) = match Agent::service_state() {
+        Ok(state) => state,
+        Err(err) => {
+            return Finding::warn(
+                "systemd",
+                format!("can't run systemctl: {}", err),
+                "run `reverseping start` under your own supervisor instead",
+            )
+        }
+    };
+
+    let detail = format!("{} is {} and {}", service, enabled, active);
+    match (enabled.as_str(), active.as_str()) {
+        ("enabled", "active") => Finding::ok("systemd", detail),
+        ("" | "not-found", _) => Finding::warn(
+            "systemd",
+            format!("{} is not installed", service),
+            "run `reverseping up <agent id>` to install the daemon",
+        ),
+        _ => Finding::fail(
+            "systemd",
+            detail,
+            format!(
+                "run `sudo systemctl enable --now {}` and check `journalctl -u {}`",
+                service, service
+            ),
+        ),
+    }
+}
+
+async fn api(conf: &AgentConfig) -> Finding {
+    let origin = &conf.api_origin;
+    let builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+    let client = match transmit::client(&conf.transport, builder) {
+        Ok(client) => client,
+        Err(err) => {
+            return Finding::fail(
+                "api",
+                err.to_string(),
+                "fix the [transport] section of the config",
+            )
+        }
+    };
+
+    // any http response at all means the api is reachable
+    match client.get(origin).send().await {
+        Ok(response) => Finding::ok("api", format!("{} answered {}", origin, response.status())),
+        Err(err) => Finding::fail(
+            "api",
+            format!("can't reach {}: {}", origin, transmit::describe(&err)),
+            "check dns, firewall rules and any proxy between this host and the internet, \
+             see transport.proxy and transport.ca_file",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(finding: &Finding) -> &str {
+        finding.fix.as_deref().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_icmp_finding() {
+        let exe = "/usr/bin/reverseping";
+        assert_eq!(
+            icmp_finding(true, false, "root: yes", exe).status,
+            Status::Ok
+        );
+        let unprivileged = icmp_finding(false, true, "root: no", exe);
+        assert_eq!(unprivileged.status, Status::Ok);
+        assert!(unprivileged.detail.starts_with("unprivileged"));
+
+        let denied = icmp_finding(false, false, "root: no", exe);
+        assert_eq!(denied.status, Status::Fail);
+        assert!(fix(&denied).contains("net.ipv4.ping_group_range"));
+        assert!(fix(&denied).contains("setcap cap_net_raw+ep /usr/bin/reverseping"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_arp_finding() {
+        assert_eq!(arp_finding(true, "reverseping").status, Status::Ok);
+        let denied = arp_finding(false, "/usr/bin/reverseping");
+        assert_eq!(denied.status, Status::Warn);
+        assert!(fix(&denied).contains("setcap cap_net_raw+ep /usr/bin/reverseping"));
+    }
+
+    #[test]
+    fn test_config_finding() {
+        let path = "/home/agent/.config/reverseping/config.toml";
+        let configured = AgentConfig {
+            agent: "agent-1".to_string(),
+            ..AgentConfig::default()
+        };
+        assert_eq!(config_finding(Ok(configured), path).status, Status::Ok);
+
+        let unconfigured = config_finding(Err(agent::Error::AgentNotConfigured.into()), path);
+        assert_eq!(unconfigured.status, Status::Warn);
+        assert!(unconfigured.detail.contains(path));
+        assert!(fix(&unconfigured).contains("reverseping enroll"));
+
+        let invalid = config_finding(Err(config::Error::Flag("api.enabled".to_string())), path);
+        assert_eq!(invalid.status, Status::Fail);
+        assert!(invalid.detail.contains("invalid --set api.enabled"));
+    }
+
+    #[test]
+    fn test_credential_finding() {
+        let path = Path::new("/home/agent/.config/reverseping/credential.json");
+        let now = Utc::now();
+        let stored = |expires_at| {
+            Ok(Some(AgentCredential {
+                agent_id: "agent-1".to_string(),
+                secret: "secret".to_string(),
+                expires_at,
+            }))
+        };
+
+        let valid = credential_finding(
+            stored(Some(now + chrono::Duration::days(30))),
+            path,
+            0o600,
+            now,
+        );
+        assert_eq!(valid.status, Status::Ok);
+        assert_eq!(
+            credential_finding(stored(None), path, 0o600, now).status,
+            Status::Ok
+        );
+
+        let not_enrolled = credential_finding(Ok(None), path, 0, now);
+        assert_eq!(not_enrolled.status, Status::Warn);
+        assert!(fix(&not_enrolled).contains("reverseping enroll"));
+
+        let unreadable = credential_finding(
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into()),
+            path,
+            0o600,
+            now,
+        );
+        assert_eq!(unreadable.status, Status::Fail);
+        assert!(fix(&unreadable).contains("remove the file"));
+
+        let exposed = credential_finding(stored(None), path, 0o644, now);
+        assert_eq!(exposed.status, Status::Fail);
+        assert!(fix(&exposed).contains("chmod 600 /home/agent/.config/reverseping/credential.json"));
+
+        let expired = credential_finding(
+            stored(Some(now - chrono::Duration::days(1))),
+            path,
+            0o600,
+            now,
+        );
+        assert_eq!(expired.status, Status::Fail);
+        assert!(fix(&expired).contains("new token"));
+    }
+}
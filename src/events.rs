@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use reverseping::DeviceEvent;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    agent::Agent,
+    discovery::{DeviceName, DiscoveredDevice},
+    notify::NotifierConfig,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("device state error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertConfig {
+    /// consecutive scans a device can miss before it is reported gone
+    #[serde(default = "missed_scans_default")]
+    pub missed_scans: u32,
+    /// MAC addresses of devices expected on the network, these never raise a new device alert
+    #[serde(default)]
+    pub known_devices: Vec<String>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+fn missed_scans_default() -> u32 {
+    3
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            missed_scans: missed_scans_default(),
+            known_devices: vec![],
+            notifiers: vec![],
+        }
+    }
+}
+
+impl AlertConfig {
+    fn is_known(&self, mac: &str) -> bool {
+        self.known_devices
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(mac))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedDevice {
+    pub ip: String,
+    pub hostname: Option<String>,
+    pub vendor: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub missed_scans: u32,
+    pub gone: bool,
+}
+
+/// Devices seen in previous scans, persisted so events survive agent restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceTracker {
+    pub devices: HashMap<String, TrackedDevice>,
+}
+
+impl DeviceTracker {
+    const STATE_FILE: &'static str = "devices.json";
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Record the results of a scan and return what changed since the last one.
+    /// The very first scan only seeds the tracker so it doesn't alert on every device.
+    pub fn update(
+        &mut self,
+        devices: &HashMap<DeviceName, DiscoveredDevice>,
+        config: &AlertConfig,
+    ) -> Vec<DeviceEvent> {
+        let now = Utc::now();
+        let first_scan = self.devices.is_empty();
+        let mut events = vec![];
+
+        for device in devices.values() {
+            let ip = device.local_address.to_string();
+
+            match self.devices.get_mut(&device.mac) {
+                Some(tracked) => {
+                    if tracked.gone {
+                        events.push(DeviceEvent::DeviceBack {
+                            mac: device.mac.clone(),
+                            ip: ip.clone(),
+                        });
+                    }
+                    if tracked.ip != ip {
+                        events.push(DeviceEvent::IpChanged {
+                            mac: device.mac.clone(),
+                            old_ip: tracked.ip.clone(),
+                            new_ip: ip.clone(),
+                        });
+                    }
+                    tracked.ip = ip;
+                    tracked.hostname = device.hostname.clone().or_else(|| tracked.hostname.take());
+                    tracked.vendor = device.vendor.clone();
+                    tracked.last_seen = now;
+                    tracked.missed_scans = 0;
+                    tracked.gone = false;
+                }
+                None => {
+                    if !first_scan && !config.is_known(&device.mac) {
+                        events.push(DeviceEvent::NewDevice {
+                            mac: device.mac.clone(),
+                            ip: ip.clone(),
+                            hostname: device.hostname.clone(),
+                            vendor: device.vendor.clone(),
+                        });
+                    }
+                    self.devices.insert(
+                        device.mac.clone(),
+                        TrackedDevice {
+                            ip,
+                            hostname: device.hostname.clone(),
+                            vendor: device.vendor.clone(),
+                            first_seen: now,
+                            last_seen: now,
+                            missed_scans: 0,
+                            gone: false,
+                        },
+                    );
+                }
+            }
+        }
+
+        let seen: HashSet<&String> = devices.values().map(|d| &d.mac).collect();
+        for (mac, tracked) in self.devices.iter_mut() {
+            if seen.contains(mac) || tracked.gone {
+                continue;
+            }
+            tracked.missed_scans += 1;
+            if tracked.missed_scans >= config.missed_scans {
+                tracked.gone = true;
+                events.push(DeviceEvent::DeviceGone {
+                    mac: mac.clone(),
+                    ip: tracked.ip.clone(),
+                    missed_scans: tracked.missed_scans,
+                });
+            }
+        }
+
+        events
+    }
+}
+
+/// Compare a scan against the saved device state and return what changed
+pub fn track(
+    config: &AlertConfig,
+    devices: &HashMap<DeviceName, DiscoveredDevice>,
+) -> Result<Vec<DeviceEvent>, Error> {
+    let mut tracker = DeviceTracker::load()?;
+    let events = tracker.update(devices, config);
+    tracker.save()?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(mac: &str, ip: &str) -> (DeviceName, DiscoveredDevice) {
+        (
+            mac.to_string(),
+            DiscoveredDevice {
+                local_address: ip.parse().unwrap(),
+                ping_ms: 1,
+                hostname: None,
+                mac: mac.to_string(),
+                vendor: None,
+                meta: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_device_events() {
+        let config = AlertConfig {
+            missed_scans: 2,
+            known_devices: vec!["AA:AA:AA:AA:AA:03".to_string()],
+            notifiers: vec![],
+        };
+        let mut tracker = DeviceTracker::default();
+
+        let scan: HashMap<_, _> = vec![device("aa:aa:aa:aa:aa:01", "10.0.0.1")]
+            .into_iter()
+            .collect();
+        assert!(tracker.update(&scan, &config).is_empty());
+
+        let scan: HashMap<_, _> = vec![
+            device("aa:aa:aa:aa:aa:01", "10.0.0.5"),
+            device("aa:aa:aa:aa:aa:02", "10.0.0.2"),
+            device("aa:aa:aa:aa:aa:03", "10.0.0.3"),
+        ]
+        .into_iter()
+        .collect();
+        let mut events = tracker.update(&scan, &config);
+        events.sort_by_key(|e| e.to_string());
+        assert_eq!(
+            events,
+            vec![
+                DeviceEvent::IpChanged {
+                    mac: "aa:aa:aa:aa:aa:01".to_string(),
+                    old_ip: "10.0.0.1".to_string(),
+                    new_ip: "10.0.0.5".to_string(),
+                },
+                DeviceEvent::NewDevice {
+                    mac: "aa:aa:aa:aa:aa:02".to_string(),
+                    ip: "10.0.0.2".to_string(),
+                    hostname: None,
+                    vendor: None,
+                },
+            ]
+        );
+
+        let empty = HashMap::new();
+        assert!(tracker.update(&empty, &config).is_empty());
+        assert_eq!(tracker.update(&empty, &config).len(), 3);
+        assert!(tracker.update(&empty, &config).is_empty());
+
+        let events = tracker.update(&scan, &config);
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|e| matches!(e, DeviceEvent::DeviceBack { .. })));
+    }
+}
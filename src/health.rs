@@ -0,0 +1,226 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use reverseping::{DnsTiming, NetworkHealth, PingStats};
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::discovery::{self, ping};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthConfig {
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// echo requests sent to each target per scan
+    #[serde(default = "ping_count_default")]
+    pub ping_count: u32,
+    /// names resolved through the system resolver to time it
+    #[serde(default = "dns_names_default")]
+    pub dns_names: Vec<String>,
+    /// hosts or IPs outside the LAN to check reachability of
+    #[serde(default = "upstream_default")]
+    pub upstream: Vec<String>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+fn ping_count_default() -> u32 {
+    5
+}
+
+fn dns_names_default() -> Vec<String> {
+    vec!["api.reverseping.net".to_string()]
+}
+
+fn upstream_default() -> Vec<String> {
+    vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()]
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: enabled_default(),
+            ping_count: ping_count_default(),
+            dns_names: dns_names_default(),
+            upstream: upstream_default(),
+        }
+    }
+}
+
+/// Measure the gateway, DNS and upstream targets concurrently
+pub async fn check(config: &HealthConfig) -> NetworkHealth {
+    let gateway = async {
+        match discovery::default_gateway() {
+            Some(ip) => Some(ping_stats(&ip.to_string(), Ok(ip), config.ping_count).await),
+            None => None,
+        }
+    };
+
+    let dns = futures::future::join_all(config.dns_names.iter().map(|name| dns_timing(name)));
+
+    let upstream = futures::future::join_all(config.upstream.iter().map(|target| async move {
+        ping_stats(target, resolve(target).await, config.ping_count).await
+    }));
+
+    let (gateway, dns, upstream) = futures::join!(gateway, dns, upstream);
+
+    NetworkHealth {
+        gateway,
+        dns,
+        upstream,
+    }
+}
+
+pub async fn resolve(target: &str) -> Result<IpAddr, String> {
+    if let Ok(ip) = target.parse() {
+        return Ok(ip);
+    }
+
+    tokio::net::lookup_host((target, 0))
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|addr| addr.ip())
+        .next()
+        .ok_or_else(|| format!("{} did not resolve", target))
+}
+
+pub async fn ping_stats(target: &str, ip: Result<IpAddr, String>, count: u32) -> PingStats {
+    let mut results = vec![];
+    if let Ok(ip) = ip {
+        for _ in 0..count {
+            results.push(
+                ping::ping(ip)
+                    .await
+                    .map(|(_, duration)| duration)
+                    .map_err(|err| err.to_string()),
+            );
+        }
+    }
+    stats(target, ip, &results)
+}
+
+/// Loss and round trip times over the echo requests sent to `ip`
+fn stats(
+    target: &str,
+    ip: Result<IpAddr, String>,
+    results: &[Result<Duration, String>],
+) -> PingStats {
+    let samples: Vec<f64> = results
+        .iter()
+        .filter_map(|result| result.as_ref().ok())
+        .map(|duration| duration.as_secs_f64() * 1000.0)
+        .collect();
+    let error = match &ip {
+        Ok(_) => results.iter().rev().find_map(|result| result.clone().err()),
+        Err(err) => Some(err.clone()),
+    };
+
+    let sent = results.len() as u32;
+    let received = samples.len() as u32;
+    let loss_pct = match sent {
+        0 => 100.0,
+        _ => 100.0 * f64::from(sent - received) / f64::from(sent),
+    };
+
+    PingStats {
+        target: target.to_string(),
+        address: ip.ok().map(|ip| ip.to_string()),
+        sent,
+        received,
+        loss_pct,
+        min_ms: samples.iter().cloned().reduce(f64::min),
+        avg_ms: match received {
+            0 => None,
+            n => Some(samples.iter().sum::<f64>() / f64::from(n)),
+        },
+        max_ms: samples.iter().cloned().reduce(f64::max),
+        // a lost echo is expected now and then, only keep errors when nothing came back
+        error: error.filter(|_| received == 0),
+    }
+}
+
+async fn dns_timing(name: &str) -> DnsTiming {
+    let mut timing = DnsTiming {
+        name: name.to_string(),
+        resolver: None,
+        response_ms: None,
+        answers: vec![],
+        error: None,
+    };
+
+    let (config, mut opts) = match trust_dns_resolver::system_conf::read_system_conf() {
+        Ok(conf) => conf,
+        Err(err) => {
+            timing.error = Some(err.to_string());
+            return timing;
+        }
+    };
+
+    // time the resolver itself, not our cache
+    opts.cache_size = 0;
+    opts.timeout = Duration::from_secs(5);
+    opts.attempts = 1;
+
+    timing.resolver = config
+        .name_servers()
+        .first()
+        .map(|ns| ns.socket_addr.ip().to_string());
+
+    let resolver = match TokioAsyncResolver::tokio(config, opts) {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            timing.error = Some(err.to_string());
+            return timing;
+        }
+    };
+
+    let start = Instant::now();
+    match resolver.lookup_ip(name).await {
+        Ok(lookup) => {
+            timing.response_ms = Some(start.elapsed().as_secs_f64() * 1000.0);
+            timing.answers = lookup.iter().map(|ip| ip.to_string()).collect();
+        }
+        Err(err) => timing.error = Some(err.to_string()),
+    }
+
+    timing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_stats() {
+        let ip = Ok("10.0.0.1".parse().unwrap());
+        let ms = |ms| Ok(Duration::from_millis(ms));
+        let lost = || Err("timed out".to_string());
+
+        let some_lost = stats("gateway", ip.clone(), &[ms(10), lost(), ms(30), lost()]);
+        assert_eq!((some_lost.sent, some_lost.received), (4, 2));
+        assert_eq!(some_lost.loss_pct, 50.0);
+        assert_eq!(some_lost.min_ms, Some(10.0));
+        assert_eq!(some_lost.avg_ms, Some(20.0));
+        assert_eq!(some_lost.max_ms, Some(30.0));
+        assert_eq!(some_lost.address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(some_lost.error, None);
+
+        let all_lost = stats("gateway", ip, &[lost(), lost()]);
+        assert_eq!((all_lost.sent, all_lost.received), (2, 0));
+        assert_eq!(all_lost.loss_pct, 100.0);
+        assert_eq!(
+            (all_lost.min_ms, all_lost.avg_ms, all_lost.max_ms),
+            (None, None, None)
+        );
+        assert_eq!(all_lost.error.as_deref(), Some("timed out"));
+
+        let unresolved = stats("nowhere.example", Err("did not resolve".to_string()), &[]);
+        assert_eq!((unresolved.sent, unresolved.loss_pct), (0, 100.0));
+        assert_eq!(unresolved.address, None);
+        assert_eq!(unresolved.error.as_deref(), Some("did not resolve"));
+    }
+}
@@ -0,0 +1,119 @@
+use reverseping::{AgentHost, DevicePing, HostInterface};
+
+use crate::discovery;
+
+/// The report entry for the host the agent runs on, keyed like scanned
+/// devices by the LAN interface's mac
+pub async fn entry() -> (String, DevicePing) {
+    let lan = discovery::network_interface().await.ok();
+    let host = AgentHost {
+        hostname: whoami::fallible::hostname().unwrap_or_default(),
+        interfaces: interfaces(),
+        os: format!("{} ({})", whoami::distro(), std::env::consts::ARCH),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: uptime_secs(),
+        load: load(),
+        memory_total_kb: meminfo("MemTotal"),
+        memory_available_kb: meminfo("MemAvailable"),
+    };
+
+    let name = lan
+        .as_ref()
+        .map(|lan| lan.mac.clone())
+        .unwrap_or_else(|| host.hostname.clone());
+    let entry = DevicePing {
+        ping_ms: None,
+        local_address: lan.as_ref().map(|lan| lan.ip.to_string()),
+        mac: lan.map(|lan| lan.mac),
+        hostname: Some(host.hostname.clone()),
+        meta: Some(host.os.clone()),
+        friendly_name: None,
+        is_agent: true,
+        agent_host: Some(host),
+    };
+    (name, entry)
+}
+
+/// every interface with an address, except loopback
+fn interfaces() -> Vec<HostInterface> {
+    ifcfg::IfCfg::get()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|iface| {
+            let ips: Vec<String> = iface
+                .addresses
+                .iter()
+                .filter_map(|addr| addr.address.map(|a| a.ip()))
+                .filter(|ip| !ip.is_loopback())
+                .map(|ip| ip.to_string())
+                .collect();
+            if ips.is_empty() {
+                return None;
+            }
+            Some(HostInterface {
+                name: iface.name,
+                mac: iface.mac,
+                ips,
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn uptime_secs() -> Option<u64> {
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+    let secs: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Some(secs as u64)
+}
+
+#[cfg(target_os = "linux")]
+fn load() -> Option<[f64; 3]> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = loadavg.split_whitespace().map(|f| f.parse().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+#[cfg(target_os = "linux")]
+fn meminfo(key: &str) -> Option<u64> {
+    parse_meminfo(&std::fs::read_to_string("/proc/meminfo").ok()?, key)
+}
+
+/// the kB value of a `/proc/meminfo` line like `MemTotal:  16314400 kB`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_meminfo(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name != key {
+            return None;
+        }
+        value.split_whitespace().next()?.parse().ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn uptime_secs() -> Option<u64> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn load() -> Option<[f64; 3]> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn meminfo(_key: &str) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16314400 kB\nMemFree:         1203944 kB\nMemAvailable:    9876543 kB\n";
+        assert_eq!(parse_meminfo(meminfo, "MemTotal"), Some(16314400));
+        assert_eq!(parse_meminfo(meminfo, "MemAvailable"), Some(9876543));
+        assert_eq!(parse_meminfo(meminfo, "SwapTotal"), None);
+    }
+}
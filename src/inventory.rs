@@ -0,0 +1,164 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use reverseping::Event;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    agent::Agent,
+    discovery::{DeviceName, DiscoveredDevice},
+    status,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("history file error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// How a device looked in one scan, `ping_ms` is None when it didn't answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub at: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub ping_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedEvent {
+    pub at: DateTime<Utc>,
+    pub event: Event,
+}
+
+/// Recent samples per device and recent events, persisted between runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    pub samples: HashMap<String, VecDeque<Sample>>,
+    pub events: VecDeque<TimedEvent>,
+}
+
+impl History {
+    const STATE_FILE: &'static str = "history.json";
+    /// two hours of scans
+    const MAX_SAMPLES: usize = 120;
+    const MAX_EVENTS: usize = 200;
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Add a scan's devices and events, devices that have been missing for
+    /// the whole window are forgotten
+    pub fn record(
+        &mut self,
+        at: DateTime<Utc>,
+        devices: &HashMap<DeviceName, DiscoveredDevice>,
+        events: &[Event],
+    ) {
+        let by_mac: HashMap<&str, &DiscoveredDevice> =
+            devices.values().map(|d| (d.mac.as_str(), d)).collect();
+        for mac in by_mac.keys() {
+            self.samples.entry(mac.to_string()).or_default();
+        }
+
+        for (mac, samples) in self.samples.iter_mut() {
+            let device = by_mac.get(mac.as_str());
+            samples.push_back(Sample {
+                at,
+                ip: device.map(|d| d.local_address.to_string()),
+                ping_ms: device.map(|d| d.ping_ms as u64),
+            });
+            while samples.len() > Self::MAX_SAMPLES {
+                samples.pop_front();
+            }
+        }
+        self.samples
+            .retain(|_, samples| samples.iter().any(|s| s.ping_ms.is_some()));
+
+        self.events.extend(events.iter().map(|event| TimedEvent {
+            at,
+            event: event.clone(),
+        }));
+        while self.events.len() > Self::MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+}
+
+/// What the running daemon knows, shared with the local api
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub devices: HashMap<DeviceName, DiscoveredDevice>,
+    pub history: History,
+    pub status: status::State,
+}
+
+impl Inventory {
+    pub fn load() -> Self {
+        Self {
+            devices: HashMap::new(),
+            history: History::load().unwrap_or_default(),
+            status: status::State::started(),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.history.save()?;
+        self.status.save()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reverseping::DeviceEvent;
+
+    #[test]
+    fn test_history_record() {
+        let device = DiscoveredDevice {
+            local_address: "10.0.0.2".parse().unwrap(),
+            ping_ms: 3,
+            hostname: None,
+            mac: "aa:aa:aa:aa:aa:01".to_string(),
+            vendor: None,
+            meta: None,
+        };
+        let scan: HashMap<_, _> = vec![(device.mac.clone(), device)].into_iter().collect();
+        let event = Event::Device(DeviceEvent::DeviceBack {
+            mac: "aa:aa:aa:aa:aa:01".to_string(),
+            ip: "10.0.0.2".to_string(),
+        });
+
+        let mut history = History::default();
+        history.record(Utc::now(), &scan, &[event]);
+        history.record(Utc::now(), &HashMap::new(), &[]);
+
+        let samples = &history.samples["aa:aa:aa:aa:aa:01"];
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].ping_ms, Some(3));
+        assert_eq!(samples[1].ping_ms, None);
+        assert!(history.events[0].event.concerns_mac("AA:AA:AA:AA:AA:01"));
+
+        for _ in 0..History::MAX_SAMPLES {
+            history.record(Utc::now(), &HashMap::new(), &[]);
+        }
+        assert!(history.samples.is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use mac_oui::Oui;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fmt::Display};
@@ -5,6 +6,88 @@ use std::{collections::HashMap, fmt::Display};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PingReport {
     pub devices: HashMap<String, DevicePing>,
+    #[serde(default)]
+    pub security_events: Vec<SecurityEvent>,
+    #[serde(default)]
+    pub network_health: Option<NetworkHealth>,
+    #[serde(default)]
+    pub checks: Vec<CheckResult>,
+    #[serde(default)]
+    pub certificates: Vec<CertificateInfo>,
+    /// when the scan finished, reports can arrive late after being spooled
+    #[serde(default)]
+    pub generated_at: Option<DateTime<Utc>>,
+    /// the agent shut down mid-scan, devices missing from it may still be up
+    #[serde(default)]
+    pub partial: bool,
+    /// set when the report was too large for one request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<ReportChunk>,
+}
+
+/// One part of a report split across requests, the API merges the parts with
+/// the same id once all `count` arrived. The first part carries everything
+/// but devices, the devices are spread over all of them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportChunk {
+    pub id: String,
+    /// from 0
+    pub index: usize,
+    pub count: usize,
+}
+
+/// How the path out of the LAN looks, to tell a LAN problem from an ISP problem
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkHealth {
+    pub gateway: Option<PingStats>,
+    pub dns: Vec<DnsTiming>,
+    pub upstream: Vec<PingStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingStats {
+    pub target: String,
+    pub address: Option<String>,
+    pub sent: u32,
+    pub received: u32,
+    pub loss_pct: f64,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// The outcome of one configured service check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub kind: String,
+    pub target: String,
+    pub up: bool,
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// The leaf certificate served on a TLS port
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub host: String,
+    pub port: u16,
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub not_after: DateTime<Utc>,
+    pub days_remaining: i64,
+    pub self_signed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsTiming {
+    pub name: String,
+    pub resolver: Option<String>,
+    pub response_ms: Option<f64>,
+    pub answers: Vec<String>,
+    pub error: Option<String>,
 }
 
 lazy_static::lazy_static! {
@@ -22,6 +105,318 @@ pub struct DevicePing {
 
     #[serde(default)]
     pub is_agent: bool,
+    /// only on the agent's own entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_host: Option<AgentHost>,
+}
+
+/// How the host the agent runs on is doing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AgentHost {
+    pub hostname: String,
+    pub interfaces: Vec<HostInterface>,
+    pub os: String,
+    pub version: String,
+    pub uptime_secs: Option<u64>,
+    /// 1, 5 and 15 minute load averages
+    pub load: Option<[f64; 3]>,
+    pub memory_total_kb: Option<u64>,
+    pub memory_available_kb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostInterface {
+    pub name: String,
+    pub mac: String,
+    pub ips: Vec<String>,
+}
+
+/// A change in the device inventory observed between two scans
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    NewDevice {
+        mac: String,
+        ip: String,
+        hostname: Option<String>,
+        vendor: Option<String>,
+    },
+    DeviceGone {
+        mac: String,
+        ip: String,
+        missed_scans: u32,
+    },
+    DeviceBack {
+        mac: String,
+        ip: String,
+    },
+    IpChanged {
+        mac: String,
+        old_ip: String,
+        new_ip: String,
+    },
+}
+
+impl Display for DeviceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceEvent::NewDevice {
+                mac,
+                ip,
+                hostname,
+                vendor,
+            } => write!(
+                f,
+                "new device {} at {} ({} - {})",
+                mac,
+                ip,
+                vendor.as_deref().unwrap_or("?"),
+                hostname.as_deref().unwrap_or("?")
+            ),
+            DeviceEvent::DeviceGone {
+                mac,
+                ip,
+                missed_scans,
+            } => write!(
+                f,
+                "device {} at {} stopped responding ({} missed scans)",
+                mac, ip, missed_scans
+            ),
+            DeviceEvent::DeviceBack { mac, ip } => write!(f, "device {} at {} is back", mac, ip),
+            DeviceEvent::IpChanged {
+                mac,
+                old_ip,
+                new_ip,
+            } => write!(f, "device {} moved from {} to {}", mac, old_ip, new_ip),
+        }
+    }
+}
+
+/// Something suspicious seen on the network, like signs of ARP spoofing or a rogue DHCP server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SecurityEvent {
+    DuplicateIp {
+        ip: String,
+        macs: Vec<String>,
+    },
+    GatewayMacChanged {
+        ip: String,
+        old_mac: String,
+        new_mac: String,
+    },
+    MacClaimsManyIps {
+        mac: String,
+        ips: Vec<String>,
+    },
+    RogueDhcpServer {
+        server: String,
+        mac: Option<String>,
+        offered_ip: String,
+    },
+}
+
+impl Display for SecurityEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecurityEvent::DuplicateIp { ip, macs } => {
+                write!(
+                    f,
+                    "{} is answered by multiple devices: {}",
+                    ip,
+                    macs.join(", ")
+                )
+            }
+            SecurityEvent::GatewayMacChanged {
+                ip,
+                old_mac,
+                new_mac,
+            } => write!(
+                f,
+                "gateway {} changed MAC address from {} to {}",
+                ip, old_mac, new_mac
+            ),
+            SecurityEvent::MacClaimsManyIps { mac, ips } => write!(
+                f,
+                "device {} claims {} addresses: {}",
+                mac,
+                ips.len(),
+                ips.join(", ")
+            ),
+            SecurityEvent::RogueDhcpServer {
+                server,
+                mac,
+                offered_ip,
+            } => write!(
+                f,
+                "unexpected DHCP server {} ({}) offered {}",
+                server,
+                mac.as_deref().unwrap_or("?"),
+                offered_ip
+            ),
+        }
+    }
+}
+
+/// A certificate that expired or is about to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CertificateEvent {
+    Expiring {
+        host: String,
+        port: u16,
+        subject: String,
+        not_after: DateTime<Utc>,
+        days_remaining: i64,
+    },
+    Expired {
+        host: String,
+        port: u16,
+        subject: String,
+        not_after: DateTime<Utc>,
+    },
+}
+
+impl Display for CertificateEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateEvent::Expiring {
+                host,
+                port,
+                subject,
+                days_remaining,
+                ..
+            } => write!(
+                f,
+                "certificate {} on {}:{} expires in {} days",
+                subject, host, port, days_remaining
+            ),
+            CertificateEvent::Expired {
+                host,
+                port,
+                subject,
+                not_after,
+            } => write!(
+                f,
+                "certificate {} on {}:{} expired on {}",
+                subject,
+                host,
+                port,
+                not_after.format("%Y-%m-%d")
+            ),
+        }
+    }
+}
+
+/// Anything the agent notifies about
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "category", rename_all = "snake_case")]
+pub enum Event {
+    Device(DeviceEvent),
+    Security(SecurityEvent),
+    Certificate(CertificateEvent),
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Device(event) => event.fmt(f),
+            Event::Security(event) => write!(f, "[security] {}", event),
+            Event::Certificate(event) => event.fmt(f),
+        }
+    }
+}
+
+impl Event {
+    /// whether the event is about the device with this mac address
+    pub fn concerns_mac(&self, mac: &str) -> bool {
+        let eq = |other: &String| other.eq_ignore_ascii_case(mac);
+        match self {
+            Event::Device(DeviceEvent::NewDevice { mac, .. })
+            | Event::Device(DeviceEvent::DeviceGone { mac, .. })
+            | Event::Device(DeviceEvent::DeviceBack { mac, .. })
+            | Event::Device(DeviceEvent::IpChanged { mac, .. })
+            | Event::Security(SecurityEvent::MacClaimsManyIps { mac, .. }) => eq(mac),
+            Event::Security(SecurityEvent::DuplicateIp { macs, .. }) => macs.iter().any(eq),
+            Event::Security(SecurityEvent::GatewayMacChanged {
+                old_mac, new_mac, ..
+            }) => eq(old_mac) || eq(new_mac),
+            Event::Security(SecurityEvent::RogueDhcpServer { mac, .. }) => {
+                mac.as_ref().is_some_and(eq)
+            }
+            Event::Certificate(_) => false,
+        }
+    }
+}
+
+/// What the API can send back in response to a report, empty when it has nothing to change
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Control {
+    #[serde(default)]
+    pub settings: RemoteSettings,
+    #[serde(default)]
+    pub commands: Vec<IssuedCommand>,
+}
+
+/// The settings fleet operators can change remotely, unset ones are left alone
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RemoteSettings {
+    pub scan_interval_secs: Option<u64>,
+    /// CIDR ranges to scan, empty to go back to the LAN interface's subnet
+    pub subnets: Option<Vec<String>>,
+    pub agent_only: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedCommand {
+    pub id: String,
+    #[serde(flatten)]
+    pub command: RemoteCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteCommand {
+    /// scan right away instead of waiting for the interval
+    Scan,
+    /// run every discovery stage against one host
+    Probe {
+        host: String,
+        #[serde(default)]
+        ports: Vec<u16>,
+    },
+    /// upload doctor findings and the recent log
+    Diagnostics,
+    /// swap the agent's credential for a new one, e.g. after a suspected leak
+    RotateCredential,
+}
+
+/// Sent back once a command ran
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResult {
+    pub id: String,
+    pub ok: bool,
+    pub output: Vec<String>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Exchanged for a credential once, enrollment tokens are short-lived and single use
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollRequest {
+    pub token: String,
+    pub hostname: String,
+    pub version: String,
+}
+
+/// What the agent authenticates its requests with, from enrolling or rotating
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentCredential {
+    pub agent_id: String,
+    /// sent as a bearer token
+    pub secret: String,
+    /// the agent rotates it before then, never expires when unset
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +448,21 @@ pub fn get_vendor_for_mac(mac: &str) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_encoding() {
+        let event = Event::Security(SecurityEvent::GatewayMacChanged {
+            ip: "192.168.1.1".to_string(),
+            old_mac: "aa:aa:aa:aa:aa:aa".to_string(),
+            new_mac: "bb:bb:bb:bb:bb:bb".to_string(),
+        });
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["category"], "security");
+        assert_eq!(json["type"], "gateway_mac_changed");
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+    }
+
     #[test]
     fn test_mac() {
         assert_eq!(
@@ -0,0 +1,320 @@
+use std::{
+    fs::File,
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::agent::Agent;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// level for the agent's own logs, dependencies only ever log warnings and errors
+    #[serde(default = "level_default")]
+    pub level: LevelFilter,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// rotate the log file once it grows past this size
+    #[serde(default = "max_size_mb_default")]
+    pub max_size_mb: u64,
+    /// rotated files kept next to the current one
+    #[serde(default = "keep_default")]
+    pub keep: usize,
+    /// log to stderr with syslog priorities instead of the log file,
+    /// defaults to on when stderr is connected to the journal
+    pub journald: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn level_default() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn max_size_mb_default() -> u64 {
+    10
+}
+
+fn keep_default() -> usize {
+    5
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: level_default(),
+            format: LogFormat::default(),
+            max_size_mb: max_size_mb_default(),
+            keep: keep_default(),
+            journald: None,
+        }
+    }
+}
+
+enum Sink {
+    /// foreground mode in a container, where the runtime collects stdout
+    Stdout,
+    /// systemd's journal reads `<priority>` prefixes from stderr and adds its own timestamps
+    Journald,
+    File {
+        path: PathBuf,
+        file: Option<File>,
+        size: u64,
+        /// echo to the terminal when run interactively
+        echo: bool,
+    },
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    max_size: u64,
+    keep: usize,
+    sink: Mutex<Sink>,
+}
+
+/// Install the global logger, falls back to stderr when the log file can't be opened
+pub fn init(config: &LogConfig, stdout: bool) {
+    let journald = config
+        .journald
+        .unwrap_or_else(|| std::env::var_os("JOURNAL_STREAM").is_some());
+
+    let sink = match (stdout, journald, Agent::log_file()) {
+        (true, _, _) => Sink::Stdout,
+        (false, false, Ok(path)) => Sink::File {
+            path,
+            file: None,
+            size: 0,
+            echo: std::io::stderr().is_terminal(),
+        },
+        _ => Sink::Journald,
+    };
+
+    let logger = Logger {
+        level: config.level,
+        format: config.format,
+        max_size: config.max_size_mb * 1024 * 1024,
+        keep: config.keep,
+        sink: Mutex::new(sink),
+    };
+
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(config.level.max(LevelFilter::Warn));
+    }
+}
+
+impl Logger {
+    fn format(&self, record: &Record) -> String {
+        match self.format {
+            LogFormat::Json => serde_json::json!({
+                "ts": chrono::Local::now().to_rfc3339(),
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+            LogFormat::Text => format!(
+                "{} {:<5} {}: {}",
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        }
+    }
+
+    /// shift `debug.log` to `debug.log.1`, `debug.log.1` to `debug.log.2` and so on
+    fn rotate(&self, path: &PathBuf) {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+        let _ = std::fs::remove_file(rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = std::fs::rename(rotated(n), rotated(n + 1));
+        }
+
+        if self.keep > 0 {
+            let _ = std::fs::rename(path, rotated(1));
+        } else {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            self.level
+        } else {
+            self.level.min(LevelFilter::Warn)
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match &mut *sink {
+            // a closed pipe mustn't take the agent down with it
+            Sink::Stdout => {
+                let _ = writeln!(std::io::stdout(), "{}", self.format(record));
+            }
+            Sink::Journald => {
+                let priority = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let line = match self.format {
+                    LogFormat::Json => self.format(record),
+                    LogFormat::Text => format!("{}: {}", record.target(), record.args()),
+                };
+                let _ = writeln!(std::io::stderr(), "<{}>{}", priority, line);
+            }
+            Sink::File {
+                path,
+                file,
+                size,
+                echo,
+            } => {
+                let line = self.format(record);
+                if *echo {
+                    let _ = writeln!(std::io::stderr(), "{}", line);
+                }
+
+                if file.is_some() && *size + line.len() as u64 + 1 > self.max_size {
+                    *file = None;
+                    self.rotate(path);
+                }
+
+                if file.is_none() {
+                    *file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .ok();
+                    *size = file
+                        .as_ref()
+                        .and_then(|f| f.metadata().ok())
+                        .map_or(0, |m| m.len());
+                }
+
+                if let Some(f) = file {
+                    if writeln!(f, "{}", line).is_ok() {
+                        *size += line.len() as u64 + 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            if let Sink::File { file: Some(f), .. } = &mut *sink {
+                let _ = f.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_config() {
+        let config: LogConfig = toml::from_str(
+            r#"
+            level = "debug"
+            format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.level, LevelFilter::Debug);
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(config.max_size_mb, 10);
+        assert_eq!(config.journald, None);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("reverseping-logging-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("debug.log");
+
+        let logger = Logger {
+            level: LevelFilter::Info,
+            format: LogFormat::Text,
+            max_size: 400,
+            keep: 2,
+            sink: Mutex::new(Sink::File {
+                path: path.clone(),
+                file: None,
+                size: 0,
+                echo: false,
+            }),
+        };
+        for n in 0..40 {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("line {:02}", n))
+                    .level(Level::Info)
+                    .target(env!("CARGO_CRATE_NAME"))
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["debug.log", "debug.log.1", "debug.log.2"]);
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        for name in &files {
+            assert!(read(name).len() <= 400);
+        }
+        // the newest lines are in the current file, the ones before in the first rotation
+        assert!(read("debug.log").ends_with("line 39\n"));
+        let previous = read("debug.log.1");
+        let last_rotated: usize = previous
+            .trim_end()
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let first_current: usize = read("debug.log")
+            .lines()
+            .next()
+            .unwrap()
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(last_rotated + 1, first_current);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
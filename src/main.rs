@@ -1,17 +1,51 @@
-use std::{collections::HashMap, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use crate::transmit::Transmitter;
 
 mod agent;
+mod api;
+mod certs;
+mod checks;
+mod config;
+mod credential;
+mod dashboard;
 mod discovery;
+mod doctor;
+mod events;
+mod health;
+mod host;
+mod inventory;
+mod logging;
+mod notify;
+mod output;
+mod probe;
+mod reload;
+mod remote;
+mod security;
+mod shutdown;
+mod spool;
+mod status;
 mod transmit;
+mod update;
 
 use agent::{Agent, AgentConfig};
+use discovery::Discovery;
+use inventory::Inventory;
+use output::OutputFormat;
+use reverseping::{Event, PingReport};
+use shutdown::Shutdown;
 use structopt::StructOpt;
+use tokio::{
+    sync::{Notify, RwLock},
+    time::Instant,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "reverseping", about = "ReversePing Agent")]
 struct Opt {
+    /// Override a config key, e.g. `--set api.enabled=true`, can be repeated
+    #[structopt(long = "set", global = true, number_of_values = 1)]
+    set: Vec<String>,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -19,22 +53,95 @@ struct Opt {
 pub enum Command {
     /// Install and Start the agent daemon in the background
     Up {
-        agent_id: String,
+        /// Agent to report as, defaults to the configured or enrolled one
+        agent_id: Option<String>,
         #[structopt(long)]
         agent_only: bool,
     },
+    /// Exchange an enrollment token for a credential and store it with the agent id,
+    /// run `up` afterwards to install the daemon
+    Enroll {
+        token: String,
+        #[structopt(long)]
+        agent_only: bool,
+    },
+    /// Manage the credential the agent authenticates with
+    Credential(CredentialCommand),
     /// Run the agent daemon in a loop
     Start {
         agent_id: Option<String>,
         #[structopt(long)]
         agent_only: bool,
+        /// Don't install or touch the system service, log to stdout, for containers
+        #[structopt(long)]
+        foreground: bool,
     },
     /// Run the agent daemon once
-    Scan { agent: String },
+    Scan {
+        /// Agent to report to, defaults to the configured one
+        agent: Option<String>,
+        /// Print the discovered devices as table, json, jsonl or csv
+        #[structopt(long)]
+        output: Option<OutputFormat>,
+        /// Only scan and print, do not transmit or notify
+        #[structopt(long)]
+        no_send: bool,
+    },
+    /// Run every discovery stage against a single host and print the results
+    Probe {
+        host: String,
+        /// Ports to fingerprint, comma separated
+        #[structopt(long, use_delimiter = true)]
+        ports: Vec<u16>,
+    },
+    /// Check the environment the agent runs in and suggest fixes
+    Doctor,
+    /// Show whether the daemon is running and what it last did
+    Status {
+        /// Exit non-zero unless the daemon is running and scanning, for liveness probes
+        #[structopt(long)]
+        live: bool,
+    },
+    /// Print the agent's log
+    Logs {
+        /// Keep printing new log lines as they are written
+        #[structopt(long, short)]
+        follow: bool,
+    },
+    /// Install the latest release, verified against the release signing key
+    Update {
+        /// Only say whether a newer release is available
+        #[structopt(long)]
+        check: bool,
+        /// Do nothing unless `update.auto` is set, for the update timer
+        #[structopt(long)]
+        auto: bool,
+    },
+    /// Inspect the agent's configuration
+    Config(ConfigCommand),
     /// Uninstall the agent daemon
     Uninstall,
 }
 
+#[derive(Debug, StructOpt)]
+pub enum CredentialCommand {
+    /// Print the agent id and when the credential expires
+    Show,
+    /// Swap the credential for a new one, the old one stops working
+    Rotate,
+    /// Revoke the credential with the API and delete it
+    Revoke,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Print the config file, or the merged config and where each value came from
+    Show {
+        #[structopt(long)]
+        effective: bool,
+    },
+}
+
 fn main() {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .thread_stack_size(3 * 1024 * 1024)
@@ -50,6 +157,18 @@ fn main() {
 async fn start() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
 
+    let config = config::load(&opt.set)
+        .map(|effective| effective.config)
+        .unwrap_or_default();
+    let foreground = matches!(
+        opt.command,
+        Command::Start {
+            foreground: true,
+            ..
+        }
+    );
+    logging::init(&config.logging, foreground);
+
     match opt.command {
         Command::Uninstall => {
             Agent::uninstall_daemon()?;
@@ -60,65 +179,472 @@ async fn start() -> Result<(), Box<dyn std::error::Error>> {
             agent_id,
             agent_only,
         } => {
-            let _ = Agent::save_agent_config(&agent_id, agent_only)?;
+            if let Some(agent_id) = agent_id {
+                Agent::save_agent_config(&agent_id, agent_only)?;
+            }
             Agent::install_daemon()?;
             Ok(())
         }
+        Command::Enroll { token, agent_only } => {
+            // installing the daemon re-runs the command as root, the token only works once
+            let transmitter = Transmitter::new("", &config.api_origin, None, &config.transport)?;
+            let credential = transmitter.enroll(&token).await?;
+            credential::save(&credential)?;
+            Agent::save_agent_config(&credential.agent_id, agent_only)?;
+            println!(
+                "enrolled as {}, run `reverseping up` to install the daemon",
+                credential.agent_id
+            );
+            Ok(())
+        }
+        Command::Credential(command) => {
+            let stored = credential::load()?.ok_or(credential::Error::NotEnrolled)?;
+            let mut transmitter = Transmitter::new(
+                &stored.agent_id,
+                &config.api_origin,
+                Some(stored.clone()),
+                &config.transport,
+            )?;
+            match command {
+                CredentialCommand::Show => {
+                    println!("agent    {}", stored.agent_id);
+                    println!("file     {}", credential::path()?.display());
+                    match stored.expires_at {
+                        Some(expires) => println!("expires  {}", expires.to_rfc3339()),
+                        None => println!("expires  never"),
+                    }
+                }
+                CredentialCommand::Rotate => {
+                    credential::rotate(&mut transmitter).await?;
+                    println!("rotated the credential, the daemon uses it from its next report");
+                }
+                CredentialCommand::Revoke => {
+                    credential::revoke(&transmitter).await?;
+                    println!("revoked, the agent can't report until it's enrolled again");
+                }
+            }
+            Ok(())
+        }
         Command::Start {
             agent_id,
             agent_only,
+            foreground,
         } => {
-            let agent = if let Some(agent) = agent_id {
-                let conf = Agent::save_agent_config(&agent, agent_only)?;
+            let mut flags = opt.set.clone();
+            if foreground {
+                // everything comes from env and args, nothing is written
+                if let Some(agent) = agent_id {
+                    flags.push(format!("agent={}", agent));
+                }
+                if agent_only {
+                    flags.push("agent_only=true".to_string());
+                }
+            } else if let Some(agent) = agent_id {
+                Agent::save_agent_config(&agent, agent_only)?;
                 Agent::install_daemon()?;
-                conf
-            } else {
-                Agent::get_agent_config()?
+            }
+            let mut agent = config::load(&flags)?.agent()?;
+
+            let context = api::Context {
+                inventory: Arc::new(RwLock::new(Inventory::load())),
+                scan_now: Arc::new(Notify::new()),
+                dashboard: agent.api.dashboard,
+                listen: agent.api.listen,
             };
 
+            if agent.api.enabled {
+                let (config, context) = (agent.api.clone(), context.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = api::serve(config, context).await {
+                        log_err(err.into());
+                    }
+                });
+            }
+
+            let mut reloader = reload::Reloader::new(flags);
+            // so `status --live` passes during the first scan
+            if let Err(err) = context.inventory.read().await.save() {
+                log_err(err);
+            }
+
+            let (trigger, shutdown) = shutdown::channel();
+            tokio::spawn(async move {
+                terminated().await;
+                trigger.request();
+            });
+            let _ = Agent::sd_notify("READY=1");
+
             loop {
-                if let Err(err) = run(&agent).await {
+                if let Some(config) = reloader.poll(&agent) {
+                    agent = config;
+                }
+
+                // a scan that never finishes stops feeding the watchdog, so systemd restarts us
+                let scan =
+                    tokio::time::timeout(Agent::SCAN_TIMEOUT, run(&agent, &context, &shutdown));
+                tokio::pin!(scan);
+                let result = tokio::select! {
+                    result = &mut scan => Some(result),
+                    _ = shutdown.requested() => {
+                        let deadline = Duration::from_secs(agent.shutdown_deadline_secs);
+                        log::info!(
+                            "stopping, giving the scan in progress {}s to wrap up",
+                            deadline.as_secs()
+                        );
+                        tokio::time::timeout(deadline, scan).await.ok()
+                    }
+                };
+                let finished = match result {
+                    Some(Ok(Err(err))) => {
+                        log_err(err);
+                        true
+                    }
+                    Some(Ok(Ok(()))) => true,
+                    Some(Err(_)) => {
+                        log::error!(
+                            "the scan didn't finish within {}s, leaving the watchdog to restart the agent",
+                            Agent::SCAN_TIMEOUT.as_secs()
+                        );
+                        false
+                    }
+                    None => {
+                        log::warn!("the scan in progress didn't finish before the deadline");
+                        false
+                    }
+                };
+                if let Err(err) = context.inventory.read().await.save() {
                     log_err(err);
                 }
-                tokio::time::sleep(Duration::from_secs(60)).await;
+                if shutdown.is_requested() {
+                    break;
+                }
+
+                // wake up well within the watchdog timeout to feed it while waiting
+                let next_scan = Instant::now() + Duration::from_secs(agent.scan_interval_secs);
+                let stop = loop {
+                    if finished {
+                        alive(&context).await;
+                    }
+                    let wake = next_scan.min(Instant::now() + Agent::WATCHDOG / 4);
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(wake) => {}
+                        _ = context.scan_now.notified() => {
+                            log::info!("scan requested");
+                            break false;
+                        }
+                        _ = shutdown.requested() => break true,
+                    }
+                    if wake >= next_scan {
+                        break false;
+                    }
+                };
+                if stop {
+                    break;
+                }
             }
+
+            let _ = Agent::sd_notify("STOPPING=1");
+            if let Err(err) = context.inventory.read().await.save() {
+                log_err(err);
+            }
+            log::info!("stopped");
+            Ok(())
+        }
+        Command::Scan {
+            agent,
+            output,
+            no_send,
+        } => {
+            let mut conf = config::load(&opt.set)?.config;
+            conf.agent = agent.unwrap_or(conf.agent);
+            conf.agent_only = false;
+            if conf.agent.is_empty() && !no_send {
+                return Err(agent::Error::AgentNotConfigured.into());
+            }
+
+            let discovery = discover(&conf, &Shutdown::never()).await?;
+
+            if let Some(format) = output {
+                let devices: Vec<_> = discovery.devices.values().collect();
+                println!("{}", output::render(format, &devices)?);
+            }
+
+            if no_send {
+                return Ok(());
+            }
+
+            let (report, _) = report(&conf, discovery, &Shutdown::never()).await;
+            Transmitter::new(
+                &conf.agent,
+                &conf.api_origin,
+                credential::load()?,
+                &conf.transport,
+            )?
+            .send(&report)
+            .await?;
+            Ok(())
         }
-        Command::Scan { agent } => {
-            let conf = AgentConfig {
-                agent,
-                agent_only: false,
+        Command::Probe { host, ports } => {
+            // the other stages still work without icmp
+            if let Err(err) = ensure_icmp() {
+                eprintln!("{}", err);
+            }
+
+            let ports = if ports.is_empty() {
+                probe::DEFAULT_PORTS.to_vec()
+            } else {
+                ports
             };
-            run(&conf).await
+            probe::probe(&host, &ports, &mut |line| println!("{}", line)).await;
+            Ok(())
+        }
+        Command::Doctor => {
+            if !doctor::run(config::load(&opt.set), &mut |line| println!("{}", line)).await {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Status { live: false } => Ok(status::print()?),
+        Command::Status { live: true } => match status::live() {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                eprintln!("{}", reason);
+                std::process::exit(1);
+            }
+        },
+        Command::Logs { follow } => Ok(status::logs(follow).await?),
+        Command::Update { check, auto } => {
+            if auto && !config.update.auto {
+                log::info!("auto updates are off");
+                return Ok(());
+            }
+            Ok(update::run(&config.update, &config.transport, check).await?)
+        }
+        Command::Config(ConfigCommand::Show { effective }) => {
+            if effective {
+                config::load(&opt.set)?.print();
+            } else {
+                let path = Agent::config_file()?;
+                println!("# {}", path.display());
+                print!("{}", std::fs::read_to_string(path).unwrap_or_default());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop with SIGTERM or ctrl-c
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
         }
     }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Tell systemd and `status --live` that the scan loop is making progress
+async fn alive(context: &api::Context) {
+    let _ = Agent::sd_notify("WATCHDOG=1");
+    if let Err(err) = context.inventory.write().await.status.beat() {
+        log_err(err.into());
+    }
 }
 
 fn log_err(e: Box<dyn std::error::Error>) {
-    let _ = Agent::write_log(format!("\n[Error] {}: {:?}", chrono::Local::now(), e));
+    log::error!("{}", e);
+}
+
+async fn run(
+    agent: &AgentConfig,
+    context: &api::Context,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let inventory = &context.inventory;
+    let started = chrono::Utc::now();
+    let discovery = match discover(agent, shutdown).await {
+        Ok(discovery) => discovery,
+        Err(err) => {
+            let mut inventory = inventory.write().await;
+            inventory.status.scanned(started, Err(err.to_string()));
+            return Err(err);
+        }
+    };
+
+    let devices = discovery.devices.clone();
+    let (report, events) = report(agent, discovery, shutdown).await;
+
+    {
+        let mut inventory = inventory.write().await;
+        inventory.status.scanned(started, Ok(devices.len()));
+        if !report.partial {
+            inventory.history.record(started, &devices, &events);
+            inventory.devices = devices;
+        }
+    }
+
+    let spool = spool::Spool::open()?;
+    let transmitter = credential::load()
+        .map_err(|err| err.to_string())
+        .and_then(|stored| {
+            Transmitter::new(&agent.agent, &agent.api_origin, stored, &agent.transport)
+                .map_err(|err| err.to_string())
+        });
+    let mut transmitter = match transmitter {
+        Ok(transmitter) => transmitter,
+        // a config mistake shouldn't lose reports, they go out once it's fixed
+        Err(err) => {
+            spool.push(&report)?;
+            inventory
+                .write()
+                .await
+                .status
+                .transmitted(Some(err.clone()));
+            return Err(err.into());
+        }
+    };
+    let sent = tokio::select! {
+        sent = transmitter.send_or_spool(&report, &spool), if !shutdown.is_requested() => sent,
+        // don't hold up exiting on the network, the report goes out after the restart
+        _ = shutdown.requested() => {
+            spool.push(&report)?;
+            log::info!(
+                "spooled the {} report for after the restart",
+                if report.partial { "partial" } else { "last" }
+            );
+            return Ok(());
+        }
+    };
+    inventory
+        .write()
+        .await
+        .status
+        .transmitted(sent.as_ref().err().map(|err| err.to_string()));
+
+    let control = sent?;
+    if shutdown.is_requested() {
+        return Ok(());
+    }
+    remote::apply(control, agent, context, &mut transmitter).await;
+
+    let due = transmitter
+        .credential()
+        .map(|current| credential::due(current, chrono::Utc::now()))
+        .unwrap_or(false);
+    if due {
+        credential::rotate(&mut transmitter).await?;
+        log::info!("rotated the agent's credential before it expires");
+    }
+    Ok(())
 }
 
-async fn run(agent: &AgentConfig) -> Result<(), Box<dyn std::error::Error>> {
-    let devices = if agent.agent_only {
-        let _ = Agent::write_log("running in agent-only mode (no local device scanning)");
-        HashMap::default()
+/// Unprivileged icmp sockets or CAP_NET_RAW are enough, only ask for root without either
+fn ensure_icmp() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(unix)]
+    if !discovery::ping::allowed() {
+        sudo::escalate_if_needed().map_err(|_| {
+            "Sending pings needs root, CAP_NET_RAW or net.ipv4.ping_group_range, \
+             run `reverseping doctor` for details"
+        })?;
+    }
+    Ok(())
+}
+
+/// Scan the local network, unless running in agent-only mode
+async fn discover(
+    agent: &AgentConfig,
+    shutdown: &Shutdown,
+) -> Result<Discovery, Box<dyn std::error::Error>> {
+    if agent.agent_only {
+        log::info!("running in agent-only mode (no local device scanning)");
+        return Ok(Discovery::default());
+    }
+
+    ensure_icmp()?;
+
+    let discovery = discovery::discover_devices(&agent.subnets, shutdown).await?;
+
+    log::info!("discovered {} devices", discovery.devices.len());
+    for device in discovery.devices.values() {
+        log::debug!("device {}", device);
+    }
+
+    Ok(discovery)
+}
+
+/// Run every check on top of a scan, send out events and build the report,
+/// checks that haven't started when shutdown is requested are skipped
+async fn report(
+    agent: &AgentConfig,
+    discovery: Discovery,
+    shutdown: &Shutdown,
+) -> (PingReport, Vec<Event>) {
+    let mut events = vec![];
+    let mut security_events = vec![];
+
+    // devices missing from a cut short scan aren't gone
+    if !agent.agent_only && !discovery.partial {
+        match events::track(&agent.alerts, &discovery.devices) {
+            Ok(device_events) => events.extend(device_events.into_iter().map(Event::Device)),
+            Err(err) => log_err(err.into()),
+        }
+
+        match security::inspect(&agent.security, &discovery).await {
+            Ok(found) => security_events = found,
+            Err(err) => log_err(err.into()),
+        }
+        events.extend(security_events.iter().cloned().map(Event::Security));
+    }
+
+    let certificates = if agent.certificates.enabled && !shutdown.is_requested() {
+        certs::collect(&agent.certificates, &discovery.devices).await
+    } else {
+        vec![]
+    };
+
+    match certs::expiry_events(&agent.certificates, &certificates) {
+        Ok(found) => events.extend(found.into_iter().map(Event::Certificate)),
+        Err(err) => log_err(err.into()),
+    }
+
+    notify::dispatch(&agent.agent, &agent.alerts.notifiers, &events).await;
+
+    let network_health = if agent.health.enabled && !shutdown.is_requested() {
+        Some(health::check(&agent.health).await)
+    } else {
+        None
+    };
+
+    let checks = if shutdown.is_requested() {
+        vec![]
     } else {
-        #[cfg(unix)]
-        sudo::escalate_if_needed().expect("Root access needed to scan devices");
-
-        let devices = discovery::discover_devices().await?;
-
-        let log = format!(
-            "\n[Log] {}: Discovered devices:\n\n{}",
-            chrono::Local::now(),
-            devices
-                .iter()
-                .map(|d| format!("{}", d.1))
-                .collect::<Vec<String>>()
-                .join("-\t\n")
-        );
-        let _ = Agent::write_log(log);
-        devices
+        checks::run_all(&agent.checks).await
     };
 
-    Ok(Transmitter::new(&agent.agent).send(devices).await?)
+    // the agent's own entry, also in agent-only mode
+    let mut devices = transmit::device_pings(discovery.devices);
+    let (name, mut agent_entry) = host::entry().await;
+    if let Some(scanned) = devices.get(&name) {
+        agent_entry.ping_ms = scanned.ping_ms;
+    }
+    devices.insert(name, agent_entry);
+
+    let report = PingReport {
+        devices,
+        security_events,
+        network_health,
+        checks,
+        certificates,
+        generated_at: Some(chrono::Utc::now()),
+        partial: discovery.partial || shutdown.is_requested(),
+        chunk: None,
+    };
+    (report, events)
 }
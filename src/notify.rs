@@ -0,0 +1,256 @@
+use std::{process::Stdio, time::Duration};
+
+use reverseping::Event;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("webhook failed: {0}")]
+    Webhook(#[from] reqwest::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("notify command exited with {0}")]
+    Command(std::process::ExitStatus),
+    #[error("smtp relay error: {0}")]
+    Smtp(String),
+    #[error("notifier timed out")]
+    Timeout,
+}
+
+/// Where events get delivered to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    /// POST the events as JSON to a URL
+    Webhook { url: String },
+    /// Run a local command with the events as JSON on stdin
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Send a plain text email through an SMTP relay that accepts unauthenticated mail
+    Email {
+        #[serde(default = "smtp_relay_default")]
+        relay: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+fn smtp_relay_default() -> String {
+    "127.0.0.1:25".to_string()
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    agent: &'a str,
+    hostname: String,
+    events: &'a [Event],
+}
+
+impl NotifierConfig {
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub async fn notify(&self, agent_id: &str, events: &[Event]) -> Result<(), Error> {
+        let notification = Notification {
+            agent: agent_id,
+            hostname: whoami::fallible::hostname().unwrap_or_default(),
+            events,
+        };
+
+        let send = async {
+            match self {
+                NotifierConfig::Webhook { url } => {
+                    reqwest::Client::new()
+                        .post(url)
+                        .json(&notification)
+                        .send()
+                        .await?
+                        .error_for_status()?;
+                    Ok(())
+                }
+                NotifierConfig::Command { command, args } => {
+                    run_command(command, args, &notification).await
+                }
+                NotifierConfig::Email { relay, from, to } => {
+                    send_email(relay, from, to, &notification).await
+                }
+            }
+        };
+
+        tokio::time::timeout(Self::TIMEOUT, send)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+/// Log the events and send them through every configured notifier
+pub async fn dispatch(agent_id: &str, notifiers: &[NotifierConfig], events: &[Event]) {
+    if events.is_empty() {
+        return;
+    }
+
+    for event in events {
+        log::info!("event: {}", event);
+    }
+
+    for notifier in notifiers {
+        if let Err(err) = notifier.notify(agent_id, events).await {
+            log::error!("notifier failed: {}", err);
+        }
+    }
+}
+
+async fn run_command(
+    command: &str,
+    args: &[String],
+    notification: &Notification<'_>,
+) -> Result<(), Error> {
+    let mut child = tokio::process::Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&serde_json::to_vec(notification)?).await?;
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(Error::Command(status));
+    }
+    Ok(())
+}
+
+async fn send_email(
+    relay: &str,
+    from: &str,
+    to: &[String],
+    notification: &Notification<'_>,
+) -> Result<(), Error> {
+    let stream = TcpStream::connect(relay).await?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    smtp_expect(&mut read, 220).await?;
+    smtp_command(
+        &mut write,
+        &mut read,
+        &format!("HELO {}", notification.hostname),
+        250,
+    )
+    .await?;
+    smtp_command(&mut write, &mut read, &format!("MAIL FROM:<{}>", from), 250).await?;
+    for rcpt in to {
+        smtp_command(&mut write, &mut read, &format!("RCPT TO:<{}>", rcpt), 250).await?;
+    }
+    smtp_command(&mut write, &mut read, "DATA", 354).await?;
+
+    let body = email_body(notification.events);
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nDate: {}\r\nSubject: [ReversePing] {} event(s) on {}\r\n\r\n{}\r\n.",
+        from,
+        to.join(", "),
+        chrono::Local::now().to_rfc2822(),
+        notification.events.len(),
+        notification.hostname,
+        body
+    );
+    smtp_command(&mut write, &mut read, &message, 250).await?;
+    smtp_command(&mut write, &mut read, "QUIT", 221).await?;
+
+    Ok(())
+}
+
+/// The events as DATA lines. Hostnames and friendly names come from the LAN, so
+/// any CR or LF in them starts a new line, and every line is dot-stuffed so
+/// none can end the message early and smuggle in smtp commands
+fn email_body(events: &[Event]) -> String {
+    events
+        .iter()
+        .flat_map(|e| {
+            e.to_string()
+                .split(['\r', '\n'])
+                .filter(|line| !line.is_empty())
+                .map(|line| match line.starts_with('.') {
+                    true => format!(".{}", line),
+                    false => line.to_string(),
+                })
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+async fn smtp_command<W, R>(write: &mut W, read: &mut R, line: &str, code: u16) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncBufRead + Unpin,
+{
+    write.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    smtp_expect(read, code).await
+}
+
+/// read a (possibly multi-line) smtp reply and check its status code
+async fn smtp_expect<R: AsyncBufRead + Unpin>(read: &mut R, code: u16) -> Result<(), Error> {
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            return Err(Error::Smtp("connection closed".to_string()));
+        }
+
+        let reply: u16 = line
+            .get(0..3)
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| Error::Smtp(format!("invalid reply: {}", line.trim())))?;
+
+        // "250-" continues a multi-line reply, "250 " ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+
+        if reply != code {
+            return Err(Error::Smtp(line.trim().to_string()));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reverseping::DeviceEvent;
+
+    #[test]
+    fn test_email_body_hostile_hostname() {
+        let events = vec![Event::Device(DeviceEvent::NewDevice {
+            mac: "02:00:00:00:00:01".to_string(),
+            ip: "10.0.0.5".to_string(),
+            hostname: Some("evil\r\n.\r\nMAIL FROM:<x@evil>\n.\rRSET".to_string()),
+            vendor: None,
+        })];
+
+        let body = email_body(&events);
+        let lines: Vec<_> = body.split("\r\n").collect();
+        assert_eq!(
+            lines,
+            vec![
+                "new device 02:00:00:00:00:01 at 10.0.0.5 (? - evil",
+                "..",
+                "MAIL FROM:<x@evil>",
+                "..",
+                "RSET)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use crate::discovery::DiscoveredDevice;
+
+/// How `scan` prints the devices it found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "unknown output format {}, expected table, json, jsonl or csv",
+                s
+            )),
+        }
+    }
+}
+
+const COLUMNS: [&str; 6] = ["mac", "vendor", "ip", "hostname", "ping_ms", "meta"];
+
+fn row(device: &DiscoveredDevice) -> [String; 6] {
+    [
+        device.mac.clone(),
+        device.vendor.clone().unwrap_or_default(),
+        device.local_address.to_string(),
+        device.hostname.clone().unwrap_or_default(),
+        device.ping_ms.to_string(),
+        device.meta.clone().unwrap_or_default(),
+    ]
+}
+
+/// Render devices sorted by IP so repeated scans are easy to diff
+pub fn render(
+    format: OutputFormat,
+    devices: &[&DiscoveredDevice],
+) -> Result<String, serde_json::Error> {
+    let mut devices = devices.to_vec();
+    devices.sort_by_key(|d| d.local_address);
+
+    let out = match format {
+        OutputFormat::Json => serde_json::to_string_pretty(&devices)?,
+        OutputFormat::Jsonl => devices
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, _>>()?
+            .join("\n"),
+        OutputFormat::Csv => std::iter::once(COLUMNS.join(","))
+            .chain(devices.iter().map(|d| {
+                row(d)
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        OutputFormat::Table => table(&devices),
+    };
+
+    Ok(out)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn table(devices: &[&DiscoveredDevice]) -> String {
+    let rows: Vec<[String; 6]> = devices.iter().map(|d| row(d)).collect();
+
+    let mut widths = COLUMNS.map(str::len);
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    let header = COLUMNS.map(|c| c.to_uppercase());
+    std::iter::once(&header)
+        .chain(rows.iter())
+        .map(|row| {
+            row.iter()
+                .zip(widths.iter())
+                .map(|(field, width)| format!("{:width$}", field, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_csv() {
+        let device = DiscoveredDevice {
+            local_address: "10.0.0.2".parse().unwrap(),
+            ping_ms: 3,
+            hostname: Some("printer.local.".to_string()),
+            mac: "60:12:8b:8f:38:ac".to_string(),
+            vendor: Some("Canon Inc".to_string()),
+            meta: Some("model=MX920, rev 2".to_string()),
+        };
+
+        assert_eq!(
+            render(OutputFormat::Csv, &[&device]).unwrap(),
+            "mac,vendor,ip,hostname,ping_ms,meta\n\
+             60:12:8b:8f:38:ac,Canon Inc,10.0.0.2,printer.local.,3,\"model=MX920, rev 2\""
+        );
+        assert_eq!(
+            render(OutputFormat::Jsonl, &[&device])
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+    }
+}
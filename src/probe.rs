@@ -0,0 +1,229 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::{
+    certs,
+    discovery::{arp_scan, reverse_dns, ssdp},
+    health,
+};
+
+/// ports fingerprinted when none are given
+pub const DEFAULT_PORTS: &[u16] = &[
+    21, 22, 23, 25, 53, 80, 110, 139, 143, 443, 445, 515, 554, 631, 1883, 3306, 3389, 5000, 5001,
+    5900, 8000, 8080, 8443, 8883, 9100,
+];
+
+const HTTP_PORTS: &[u16] = &[80, 5000, 8000, 8080];
+const TLS_PORTS: &[u16] = &[443, 5001, 8443, 8883];
+
+type Stage = Result<Vec<String>, String>;
+
+/// Run every discovery stage against one host and hand `out` what each one found, line by line
+pub async fn probe(target: &str, ports: &[u16], out: &mut dyn FnMut(String)) {
+    let mut print_stage = |name: &str, stage: &Stage| {
+        out(format!("== {}", name));
+        match stage {
+            Ok(lines) if lines.is_empty() => out("   (nothing found)".to_string()),
+            Ok(lines) => lines.iter().for_each(|line| out(format!("   {}", line))),
+            Err(err) => out(format!("   error: {}", err)),
+        }
+        out(String::new());
+    };
+
+    let ip = health::resolve(target).await;
+    print_stage("resolve", &ip.clone().map(|ip| vec![ip.to_string()]));
+
+    let ip = match ip {
+        Ok(ip) => ip,
+        Err(_) => return,
+    };
+
+    print_stage("ping", &ping(ip).await);
+    print_stage("mdns", &mdns(ip).await);
+    print_stage("reverse dns", &reverse(ip).await);
+
+    let (lines, mac) = arp(ip).await;
+    print_stage("arp", &Ok(lines));
+
+    let vendor = match mac {
+        Some(mac) => Ok(reverseping::get_vendor_for_mac(&mac).into_iter().collect()),
+        None => Err("no mac address to look up".to_string()),
+    };
+    print_stage("vendor", &vendor);
+
+    print_stage("ssdp", &upnp(ip).await);
+    print_stage("ports", &fingerprint(target, ip, ports).await);
+}
+
+async fn ping(ip: IpAddr) -> Stage {
+    let stats = health::ping_stats(&ip.to_string(), Ok(ip), 4).await;
+    if let (0, Some(err)) = (stats.received, &stats.error) {
+        return Err(err.clone());
+    }
+
+    let ms = |v: Option<f64>| {
+        v.map(|v| format!("{:.1}ms", v))
+            .unwrap_or_else(|| "-".into())
+    };
+    Ok(vec![format!(
+        "{}/{} replies, {:.0}% loss, min {} avg {} max {}",
+        stats.received,
+        stats.sent,
+        stats.loss_pct,
+        ms(stats.min_ms),
+        ms(stats.avg_ms),
+        ms(stats.max_ms)
+    )])
+}
+
+async fn mdns(ip: IpAddr) -> Stage {
+    let host = tokio::time::timeout(Duration::from_secs(5), reverse_dns::query_host(ip))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(host
+        .hostname
+        .map(|name| format!("hostname: {}", name))
+        .into_iter()
+        .chain(host.meta.into_iter().map(|meta| format!("txt: {}", meta)))
+        .collect())
+}
+
+async fn reverse(ip: IpAddr) -> Stage {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string())?;
+    let names = resolver
+        .reverse_lookup(ip)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(names.iter().map(|name| name.to_string()).collect())
+}
+
+/// the arp stage output and the answering mac, if any
+async fn arp(ip: IpAddr) -> (Vec<String>, Option<String>) {
+    let host = reverse_dns::DiscoveredHost {
+        ip,
+        ping_duration: Duration::default(),
+        hostname: None,
+        meta: vec![],
+    };
+
+    // without raw sockets the scan reads the kernel's neighbour table instead of
+    // letting the arp client fail to open one
+    let mut lines = vec![];
+    #[cfg(target_os = "linux")]
+    if !arp_scan::allowed() {
+        lines.push("no raw socket access, using the kernel's neighbour table".to_string());
+    }
+
+    let (hosts, claims) = arp_scan::scan(vec![host]).await;
+
+    let mac = hosts.into_iter().next().map(|h| h.mac);
+    lines.extend(mac.iter().map(|mac| format!("mac: {}", mac)));
+
+    if let Some(macs) = claims.get(&ip).filter(|macs| macs.len() > 1) {
+        lines.push(format!(
+            "warning: answered by multiple macs: {}",
+            macs.iter().cloned().collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    (lines, mac)
+}
+
+async fn upnp(ip: IpAddr) -> Stage {
+    let services = ssdp::discover_services().await.map_err(|e| e.to_string())?;
+
+    let service = match services.values().find(|service| service.ip == ip) {
+        Some(service) => service,
+        None => return Ok(vec![]),
+    };
+
+    let field =
+        |name: &str, value: &Option<String>| value.as_ref().map(|v| format!("{}: {}", name, v));
+
+    Ok(
+        std::iter::once(Some(format!("location: {}", service.location)))
+            .chain(vec![
+                field("friendly name", &service.friendly_name),
+                field("model", &service.model_name),
+                field("manufacturer", &service.vendor),
+                field("description error", &service.error),
+            ])
+            .flatten()
+            .collect(),
+    )
+}
+
+async fn fingerprint(target: &str, ip: IpAddr, ports: &[u16]) -> Stage {
+    let mut open: Vec<(u16, String)> = futures::stream::iter(ports.iter().cloned())
+        .map(|port| async move {
+            let banner = banner(target, SocketAddr::new(ip, port)).await?;
+            Some((port, banner))
+        })
+        .buffer_unordered(32)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await;
+
+    open.sort();
+    Ok(open
+        .into_iter()
+        .map(|(port, banner)| format!("{}/tcp open  {}", port, banner))
+        .collect())
+}
+
+/// connect to a port and try to identify what's listening, None if it is closed
+async fn banner(target: &str, addr: SocketAddr) -> Option<String> {
+    let timeout = Duration::from_secs(1);
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .ok()?
+        .ok()?;
+
+    if TLS_PORTS.contains(&addr.port()) {
+        drop(stream);
+        return Some(match certs::fetch(target, addr.port()).await {
+            Ok(cert) => format!(
+                "tls {} (issuer {}, {} days left)",
+                cert.subject, cert.issuer, cert.days_remaining
+            ),
+            Err(err) => format!("tls? {}", err),
+        });
+    }
+
+    if HTTP_PORTS.contains(&addr.port()) {
+        let request = format!("HEAD / HTTP/1.0\r\nHost: {}\r\n\r\n", target);
+        let _ = stream.write_all(request.as_bytes()).await;
+    }
+
+    // server-first protocols like ssh, ftp and smtp greet us on connect
+    let mut buf = [0u8; 512];
+    let read = tokio::time::timeout(timeout, stream.read(&mut buf))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or(0);
+
+    let response = String::from_utf8_lossy(&buf[..read]);
+    let line = response
+        .lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("server:"))
+        .or_else(|| response.lines().next())
+        .unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(80)
+        .collect();
+
+    Some(line)
+}
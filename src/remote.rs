@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use ipnetwork::IpNetwork;
+use reverseping::{CommandResult, Control, IssuedCommand, RemoteCommand, RemoteSettings};
+use thiserror::Error;
+
+use crate::{
+    agent::{Agent, AgentConfig},
+    api, config, credential,
+    discovery::ping,
+    doctor, probe,
+    transmit::Transmitter,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid remote setting: {0}")]
+    Invalid(String),
+}
+
+/// scanning more often than this would never let a scan finish
+const MIN_SCAN_INTERVAL_SECS: u64 = 10;
+/// the devices and checks shouldn't go unseen for longer than a day
+const MAX_SCAN_INTERVAL_SECS: u64 = 24 * 60 * 60;
+/// the largest range the API can ask to scan
+const MIN_PREFIX: u8 = 16;
+/// log lines uploaded with diagnostics
+const LOG_LINES: usize = 200;
+
+/// Save the settings the API changed to config.toml, where the reloader picks
+/// them up before the next scan, then run the commands it issued and report back
+pub async fn apply(
+    control: Control,
+    agent: &AgentConfig,
+    context: &api::Context,
+    transmitter: &mut Transmitter,
+) {
+    match changes(&control.settings, agent) {
+        Ok(changes) if changes.is_empty() => {}
+        Ok(changes) => {
+            for (key, value) in &changes {
+                log::info!("api set {} = {}", key, value);
+            }
+            if let Err(err) = Agent::set_config_values(changes) {
+                log::error!("failed to save settings from the api: {}", err);
+            }
+        }
+        Err(err) => log::error!("ignoring settings from the api, {}", err),
+    }
+
+    for issued in control.commands {
+        log::info!(
+            "running command {} from the api: {:?}",
+            issued.id,
+            issued.command
+        );
+        let result = run(issued, context, transmitter).await;
+        if let Err(err) = transmitter.send_result(&result).await {
+            log::error!(
+                "failed to send the result of command {}: {}",
+                result.id,
+                err
+            );
+        }
+    }
+}
+
+/// The config.toml values that differ from the running config, nothing if any is invalid
+fn changes(
+    settings: &RemoteSettings,
+    agent: &AgentConfig,
+) -> Result<Vec<(&'static str, toml::Value)>, Error> {
+    let mut changes = vec![];
+
+    if let Some(secs) = settings.scan_interval_secs {
+        if !(MIN_SCAN_INTERVAL_SECS..=MAX_SCAN_INTERVAL_SECS).contains(&secs) {
+            return Err(Error::Invalid(format!(
+                "scan interval {}s is outside {}s to {}s",
+                secs, MIN_SCAN_INTERVAL_SECS, MAX_SCAN_INTERVAL_SECS
+            )));
+        }
+        if secs != agent.scan_interval_secs {
+            changes.push(("scan_interval_secs", toml::Value::Integer(secs as i64)));
+        }
+    }
+    let interval = settings
+        .scan_interval_secs
+        .unwrap_or(agent.scan_interval_secs);
+
+    let subnets = match &settings.subnets {
+        Some(subnets) => subnets
+            .iter()
+            .map(|subnet| match subnet.parse::<IpNetwork>() {
+                Ok(IpNetwork::V4(network)) if network.prefix() >= MIN_PREFIX => {
+                    Ok(IpNetwork::V4(network))
+                }
+                Ok(_) => Err(Error::Invalid(format!(
+                    "{} isn't an IPv4 range of a /{} or smaller",
+                    subnet, MIN_PREFIX
+                ))),
+                Err(_) => Err(Error::Invalid(format!("{} isn't a CIDR range", subnet))),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => agent.subnets.clone(),
+    };
+    let sweep: Duration = subnets
+        .iter()
+        .map(|network| ping::sweep_time(*network))
+        .sum();
+    let rescheduled = settings.scan_interval_secs.is_some() || settings.subnets.is_some();
+    // every scan would be abandoned and the watchdog would keep restarting the agent
+    if rescheduled && sweep > Agent::SCAN_TIMEOUT {
+        return Err(Error::Invalid(format!(
+            "sweeping the subnets takes up to {}s, scans are abandoned after {}s",
+            sweep.as_secs(),
+            Agent::SCAN_TIMEOUT.as_secs()
+        )));
+    }
+    // the next scan would start before the sweep of the last one is done
+    if rescheduled && sweep.as_secs() > interval {
+        return Err(Error::Invalid(format!(
+            "sweeping the subnets takes up to {}s, longer than the {}s scan interval",
+            sweep.as_secs(),
+            interval
+        )));
+    }
+    if subnets != agent.subnets {
+        let values = subnets
+            .iter()
+            .map(|network| toml::Value::String(network.to_string()))
+            .collect();
+        changes.push(("subnets", toml::Value::Array(values)));
+    }
+
+    if let Some(agent_only) = settings.agent_only {
+        if agent_only != agent.agent_only {
+            changes.push(("agent_only", toml::Value::Boolean(agent_only)));
+        }
+    }
+
+    Ok(changes)
+}
+
+async fn run(
+    issued: IssuedCommand,
+    context: &api::Context,
+    transmitter: &mut Transmitter,
+) -> CommandResult {
+    let mut output = vec![];
+    let ok = match issued.command {
+        RemoteCommand::Scan => {
+            context.scan_now.notify_one();
+            output.push("scan requested".to_string());
+            true
+        }
+        RemoteCommand::Probe { host, ports } => {
+            let ports = if ports.is_empty() {
+                probe::DEFAULT_PORTS.to_vec()
+            } else {
+                ports
+            };
+            probe::probe(&host, &ports, &mut |line| output.push(line)).await;
+            true
+        }
+        RemoteCommand::Diagnostics => {
+            let ok = doctor::run(config::load(&[]), &mut |line| output.push(line)).await;
+
+            let status = context.inventory.read().await.status.clone();
+            output.push(String::new());
+            output.extend(
+                serde_json::to_string_pretty(&status)
+                    .unwrap_or_default()
+                    .lines()
+                    .map(String::from),
+            );
+
+            output.push(String::new());
+            match Agent::log_file().map(std::fs::read_to_string) {
+                Ok(Ok(log)) => {
+                    let lines: Vec<_> = log.lines().collect();
+                    let start = lines.len().saturating_sub(LOG_LINES);
+                    output.extend(lines[start..].iter().map(|line| line.to_string()));
+                }
+                _ => {
+                    output.push("no log file, the agent logs to the journal or stdout".to_string())
+                }
+            }
+            ok
+        }
+        RemoteCommand::RotateCredential => match credential::rotate(transmitter).await {
+            Ok(()) => {
+                output.push("credential rotated".to_string());
+                true
+            }
+            Err(err) => {
+                output.push(err.to_string());
+                false
+            }
+        },
+    };
+
+    CommandResult {
+        id: issued.id,
+        ok,
+        output,
+        finished_at: Utc::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_settings() {
+        let agent = AgentConfig::default();
+        let control: Control = serde_json::from_str(
+            r#"{
+                "settings": {"scan_interval_secs": 300, "subnets": ["10.0.0.0/24"]},
+                "commands": [{"id": "1", "type": "probe", "host": "10.0.0.2"}]
+            }"#,
+        )
+        .unwrap();
+
+        let found = changes(&control.settings, &agent).unwrap();
+        assert_eq!(found[0], ("scan_interval_secs", toml::Value::Integer(300)));
+        assert_eq!(found[1].0, "subnets");
+        assert_eq!(
+            control.commands[0].command,
+            RemoteCommand::Probe {
+                host: "10.0.0.2".to_string(),
+                ports: vec![]
+            }
+        );
+
+        let too_big = RemoteSettings {
+            subnets: Some(vec!["10.0.0.0/8".to_string()]),
+            ..RemoteSettings::default()
+        };
+        assert!(changes(&too_big, &agent).is_err());
+
+        // a /16 takes almost 11 minutes to sweep
+        let too_slow = RemoteSettings {
+            scan_interval_secs: Some(600),
+            subnets: Some(vec!["10.0.0.0/16".to_string()]),
+            ..RemoteSettings::default()
+        };
+        assert!(changes(&too_slow, &agent).is_err());
+        let slow_enough = RemoteSettings {
+            scan_interval_secs: Some(900),
+            ..too_slow
+        };
+        assert!(changes(&slow_enough, &agent).is_ok());
+        let never_finishes = RemoteSettings {
+            scan_interval_secs: Some(MAX_SCAN_INTERVAL_SECS),
+            subnets: Some(vec!["10.0.0.0/16".to_string(), "10.1.0.0/16".to_string()]),
+            ..RemoteSettings::default()
+        };
+        assert!(changes(&never_finishes, &agent).is_err());
+
+        let too_rare = RemoteSettings {
+            scan_interval_secs: Some(MAX_SCAN_INTERVAL_SECS + 1),
+            ..RemoteSettings::default()
+        };
+        assert!(changes(&too_rare, &agent).is_err());
+    }
+}
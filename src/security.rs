@@ -0,0 +1,326 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    time::Duration,
+};
+
+use reverseping::SecurityEvent;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    agent::Agent,
+    discovery::{dhcp, Discovery},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("security state error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// how many IPs a single MAC address can claim before it is reported
+    #[serde(default = "max_ips_per_mac_default")]
+    pub max_ips_per_mac: usize,
+    /// MAC addresses allowed to answer for other IPs, e.g. routers doing proxy ARP
+    #[serde(default)]
+    pub trusted_macs: Vec<String>,
+    #[serde(default)]
+    pub dhcp: DhcpConfig,
+}
+
+fn max_ips_per_mac_default() -> usize {
+    4
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            max_ips_per_mac: max_ips_per_mac_default(),
+            trusted_macs: vec![],
+            dhcp: DhcpConfig::default(),
+        }
+    }
+}
+
+/// Rogue DHCP server detection, off by default since it sends a DHCPDISCOVER every scan
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DhcpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// servers allowed to hand out leases, when empty any second server is reported
+    #[serde(default)]
+    pub expected_servers: Vec<IpAddr>,
+    /// how long to collect offers for
+    #[serde(default = "dhcp_window_default")]
+    pub window_secs: u64,
+}
+
+fn dhcp_window_default() -> u64 {
+    5
+}
+
+impl Default for DhcpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            expected_servers: vec![],
+            window_secs: dhcp_window_default(),
+        }
+    }
+}
+
+impl SecurityConfig {
+    fn is_trusted(&self, mac: &str) -> bool {
+        self.trusted_macs
+            .iter()
+            .any(|trusted| trusted.eq_ignore_ascii_case(mac))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Gateway {
+    ip: String,
+    mac: String,
+}
+
+/// What the network looked like last time, persisted between runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityState {
+    gateway: Option<Gateway>,
+}
+
+impl SecurityState {
+    const STATE_FILE: &'static str = "security.json";
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Look for IP/MAC anomalies in the ARP traffic seen during a scan
+    pub fn check(&mut self, config: &SecurityConfig, discovery: &Discovery) -> Vec<SecurityEvent> {
+        let mut events = vec![];
+
+        // one ip answered by several macs
+        for (ip, macs) in &discovery.arp_claims {
+            if macs.iter().filter(|mac| !config.is_trusted(mac)).count() > 1 {
+                events.push(SecurityEvent::DuplicateIp {
+                    ip: ip.to_string(),
+                    macs: macs.iter().cloned().collect(),
+                });
+            }
+        }
+
+        // one mac claiming many ips
+        let mut ips_by_mac = HashMap::<&String, BTreeSet<String>>::new();
+        for (ip, macs) in &discovery.arp_claims {
+            for mac in macs {
+                ips_by_mac.entry(mac).or_default().insert(ip.to_string());
+            }
+        }
+        for (mac, ips) in ips_by_mac {
+            if ips.len() > config.max_ips_per_mac && !config.is_trusted(mac) {
+                events.push(SecurityEvent::MacClaimsManyIps {
+                    mac: mac.clone(),
+                    ips: ips.into_iter().collect(),
+                });
+            }
+        }
+
+        // the gateway's mac changed since the last scan
+        let gateway = discovery
+            .gateway
+            .and_then(|ip| discovery.arp_claims.get(&ip).map(|macs| (ip, macs)));
+
+        if let Some((ip, macs)) = gateway {
+            let ip = ip.to_string();
+
+            if let Some(known) = self.gateway.as_ref().filter(|gw| gw.ip == ip) {
+                for mac in macs.iter().filter(|mac| **mac != known.mac) {
+                    events.push(SecurityEvent::GatewayMacChanged {
+                        ip: ip.clone(),
+                        old_mac: known.mac.clone(),
+                        new_mac: mac.clone(),
+                    });
+                }
+            }
+
+            // only trust an unambiguous answer as the new gateway
+            if let [mac] = macs.iter().collect::<Vec<_>>().as_slice() {
+                self.gateway = Some(Gateway {
+                    ip,
+                    mac: mac.to_string(),
+                });
+            }
+        }
+
+        events
+    }
+}
+
+/// Offers from servers that aren't expected to hand out leases on this network
+fn rogue_dhcp_servers(
+    config: &DhcpConfig,
+    offers: &[dhcp::DhcpOffer],
+    discovery: &Discovery,
+) -> Vec<SecurityEvent> {
+    let servers: BTreeSet<IpAddr> = offers.iter().map(|o| IpAddr::V4(o.server)).collect();
+
+    offers
+        .iter()
+        .filter(|offer| {
+            let server = IpAddr::V4(offer.server);
+            if config.expected_servers.is_empty() {
+                // without a list the only thing we can tell is that there's more than one
+                servers.len() > 1
+            } else {
+                !config.expected_servers.contains(&server)
+            }
+        })
+        .map(|offer| SecurityEvent::RogueDhcpServer {
+            server: offer.server.to_string(),
+            mac: discovery
+                .arp_claims
+                .get(&IpAddr::V4(offer.server))
+                .and_then(|macs| macs.iter().next().cloned()),
+            offered_ip: offer.offered_ip.to_string(),
+        })
+        .collect()
+}
+
+/// Compare a scan against the saved security state and return any anomalies
+pub async fn inspect(
+    config: &SecurityConfig,
+    discovery: &Discovery,
+) -> Result<Vec<SecurityEvent>, Error> {
+    let mut state = SecurityState::load()?;
+    let mut events = state.check(config, discovery);
+    state.save()?;
+
+    if config.dhcp.enabled {
+        let window = Duration::from_secs(config.dhcp.window_secs);
+        let probes = discovery
+            .interfaces
+            .iter()
+            .map(|iface| async move { (iface, dhcp::discover_servers(iface, window).await) });
+        // each interface has its own dhcp servers, and the arp events are already saved
+        // as seen so a failing probe mustn't drop them
+        for (iface, offers) in futures::future::join_all(probes).await {
+            match offers {
+                Ok(offers) => events.extend(rogue_dhcp_servers(&config.dhcp, &offers, discovery)),
+                Err(err) => log::error!("rogue dhcp detection on {} failed: {}", iface.name, err),
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(pairs: &[(&str, &str)]) -> Discovery {
+        let mut discovery = Discovery {
+            gateway: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        for (ip, mac) in pairs {
+            discovery
+                .arp_claims
+                .entry(ip.parse().unwrap())
+                .or_default()
+                .insert(mac.to_string());
+        }
+        discovery
+    }
+
+    #[test]
+    fn test_security_events() {
+        let config = SecurityConfig {
+            max_ips_per_mac: 2,
+            ..Default::default()
+        };
+        let mut state = SecurityState::default();
+
+        let scan = claims(&[
+            ("10.0.0.1", "aa:aa:aa:aa:aa:01"),
+            ("10.0.0.2", "aa:aa:aa:aa:aa:02"),
+        ]);
+        assert!(state.check(&config, &scan).is_empty());
+
+        let scan = claims(&[
+            ("10.0.0.1", "aa:aa:aa:aa:aa:01"),
+            ("10.0.0.1", "aa:aa:aa:aa:aa:66"),
+            ("10.0.0.2", "aa:aa:aa:aa:aa:66"),
+            ("10.0.0.3", "aa:aa:aa:aa:aa:66"),
+        ]);
+        let events = state.check(&config, &scan);
+        assert_eq!(events.len(), 3);
+        assert!(events.contains(&SecurityEvent::DuplicateIp {
+            ip: "10.0.0.1".to_string(),
+            macs: vec![
+                "aa:aa:aa:aa:aa:01".to_string(),
+                "aa:aa:aa:aa:aa:66".to_string()
+            ],
+        }));
+        assert!(events.contains(&SecurityEvent::GatewayMacChanged {
+            ip: "10.0.0.1".to_string(),
+            old_mac: "aa:aa:aa:aa:aa:01".to_string(),
+            new_mac: "aa:aa:aa:aa:aa:66".to_string(),
+        }));
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, SecurityEvent::MacClaimsManyIps { ips, .. } if ips.len() == 3)));
+
+        let config = SecurityConfig {
+            trusted_macs: vec!["AA:AA:AA:AA:AA:66".to_string()],
+            ..config
+        };
+        let events = state.check(&config, &scan);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_rogue_dhcp_servers() {
+        let offer = |server: [u8; 4]| dhcp::DhcpOffer {
+            server: server.into(),
+            offered_ip: [10, 0, 0, 50].into(),
+            relay: None,
+        };
+        let discovery = claims(&[("10.0.0.66", "aa:aa:aa:aa:aa:66")]);
+
+        let mut config = DhcpConfig::default();
+        assert!(rogue_dhcp_servers(&config, &[offer([10, 0, 0, 1])], &discovery).is_empty());
+
+        let offers = [offer([10, 0, 0, 1]), offer([10, 0, 0, 66])];
+        assert_eq!(rogue_dhcp_servers(&config, &offers, &discovery).len(), 2);
+
+        config.expected_servers = vec!["10.0.0.1".parse().unwrap()];
+        assert_eq!(
+            rogue_dhcp_servers(&config, &offers, &discovery),
+            vec![SecurityEvent::RogueDhcpServer {
+                server: "10.0.0.66".to_string(),
+                mac: Some("aa:aa:aa:aa:aa:66".to_string()),
+                offered_ip: "10.0.0.50".to_string(),
+            }]
+        );
+    }
+}
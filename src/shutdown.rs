@@ -0,0 +1,43 @@
+use tokio::sync::watch;
+
+/// Asks a scan in progress to wind down
+pub struct Trigger(watch::Sender<bool>);
+
+/// Checked by the scan before launching more probes, clones all see the same request
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+pub fn channel() -> (Trigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (Trigger(sender), Shutdown(receiver))
+}
+
+impl Trigger {
+    pub fn request(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+impl Shutdown {
+    /// for one-off commands that are simply killed
+    pub fn never() -> Self {
+        channel().1
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown was requested, never if it can't be anymore
+    pub async fn requested(&self) {
+        let mut receiver = self.0.clone();
+        loop {
+            if *receiver.borrow_and_update() {
+                return;
+            }
+            if receiver.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use reverseping::PingReport;
+use thiserror::Error;
+
+use crate::agent::Agent;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("spooled report error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Reports that couldn't be sent, kept on disk and retried oldest first
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    const DIR: &'static str = "spool";
+    /// an hour of scans, older reports are dropped first
+    const MAX_REPORTS: usize = 60;
+
+    pub fn open() -> Result<Self, Error> {
+        let dir = Agent::data_file(Self::DIR)?;
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// spooled report files, oldest first
+    pub fn pending(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.pending().map(|paths| paths.len()).unwrap_or(0)
+    }
+
+    pub fn push(&self, report: &PingReport) -> Result<(), Error> {
+        let millis = report
+            .generated_at
+            .unwrap_or_else(chrono::Utc::now)
+            .timestamp_millis();
+        let path = self.dir.join(format!("{:016}.json", millis));
+        std::fs::write(path, serde_json::to_string(report)?)?;
+
+        let pending = self.pending()?;
+        let excess = pending.len().saturating_sub(Self::MAX_REPORTS);
+        for path in &pending[..excess] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, path: &Path) -> Result<PingReport, Error> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<(), Error> {
+        Ok(std::fs::remove_file(path)?)
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{agent::Agent, spool::Spool};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Agent(#[from] crate::agent::Error),
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("status file error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Spool(#[from] crate::spool::Error),
+    #[error("no log at {}, the agent logs to the journal or stdout", .0.display())]
+    NoLog(std::path::PathBuf),
+}
+
+/// What the daemon last did, written after every run so `status` can show it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    pub pid: u32,
+    pub started_at: Option<DateTime<Utc>>,
+    /// when the scan loop last made progress, after a scan and while waiting for the next
+    pub heartbeat: Option<DateTime<Utc>>,
+    pub last_scan: Option<DateTime<Utc>>,
+    pub scan_duration_ms: Option<u64>,
+    pub scan_error: Option<String>,
+    pub devices: usize,
+    pub last_transmit: Option<DateTime<Utc>>,
+    pub transmit_error: Option<String>,
+}
+
+impl State {
+    const STATE_FILE: &'static str = "status.json";
+    /// a daemon without a heartbeat for this long is stuck, same as the systemd watchdog
+    const LIVENESS: Duration = Agent::WATCHDOG;
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Agent::data_file(Self::STATE_FILE)?;
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// a fresh state for a daemon that just started
+    pub fn started() -> Self {
+        Self {
+            pid: std::process::id(),
+            started_at: Some(Utc::now()),
+            ..Self::load().unwrap_or_default()
+        }
+    }
+
+    pub fn scanned(&mut self, started: DateTime<Utc>, result: Result<usize, String>) {
+        self.last_scan = Some(started);
+        self.scan_duration_ms = Some((Utc::now() - started).num_milliseconds().max(0) as u64);
+        match result {
+            Ok(devices) => {
+                self.devices = devices;
+                self.scan_error = None;
+            }
+            Err(err) => self.scan_error = Some(err),
+        }
+    }
+
+    /// record that the daemon is still responsive
+    pub fn beat(&mut self) -> Result<(), Error> {
+        self.heartbeat = Some(Utc::now());
+        self.save()
+    }
+
+    pub fn transmitted(&mut self, error: Option<String>) {
+        self.last_transmit = Some(Utc::now());
+        self.transmit_error = error;
+    }
+}
+
+/// Whether the daemon is running and responsive, for container liveness probes
+pub fn live() -> Result<(), String> {
+    let state = State::load().map_err(|err| err.to_string())?;
+    if state.started_at.is_none() {
+        return Err("daemon has not run yet".to_string());
+    }
+
+    #[cfg(target_os = "linux")]
+    if !std::path::Path::new("/proc")
+        .join(state.pid.to_string())
+        .exists()
+    {
+        return Err(format!("daemon pid {} is not running", state.pid));
+    }
+
+    // the first heartbeat comes after the first scan
+    let active = state.heartbeat.max(state.started_at).unwrap_or_default();
+    let idle = (Utc::now() - active).to_std().unwrap_or_default();
+    if idle > State::LIVENESS {
+        return Err(format!("no heartbeat for {}s", idle.as_secs()));
+    }
+    Ok(())
+}
+
+fn ago(time: &Option<DateTime<Utc>>) -> String {
+    match time {
+        Some(time) => {
+            let secs = (Utc::now() - *time).num_seconds().max(0);
+            let elapsed = match secs {
+                0..=119 => format!("{}s", secs),
+                120..=7199 => format!("{}m", secs / 60),
+                _ => format!("{}h", secs / 3600),
+            };
+            format!(
+                "{} ({} ago)",
+                time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                elapsed
+            )
+        }
+        None => "never".to_string(),
+    }
+}
+
+/// Print the daemon's unit state and what it last did
+pub fn print() -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    match Agent::service_state() {
+        Ok((enabled, _)) if enabled.is_empty() || enabled == "not-found" => {
+            println!("service:        not installed")
+        }
+        Ok((enabled, active)) => println!(
+            "service:        {} {}, {}",
+            Agent::service_name(),
+            enabled,
+            active
+        ),
+        Err(err) => println!("service:        unknown ({})", err),
+    }
+
+    let state = State::load()?;
+    if state.started_at.is_none() {
+        println!("daemon:         has not run yet");
+    } else {
+        println!(
+            "daemon:         pid {}, started {}",
+            state.pid,
+            ago(&state.started_at)
+        );
+        println!("heartbeat:      {}", ago(&state.heartbeat));
+    }
+
+    let scan = match (&state.scan_error, state.scan_duration_ms) {
+        (Some(err), _) => format!("failed: {}", err),
+        (None, Some(ms)) => format!("{} devices in {:.1}s", state.devices, ms as f64 / 1000.0),
+        (None, None) => String::new(),
+    };
+    println!("last scan:      {} {}", ago(&state.last_scan), scan);
+
+    let transmit = match (&state.transmit_error, state.last_transmit) {
+        (Some(err), _) => format!("failed: {}", err),
+        (None, Some(_)) => "ok".to_string(),
+        (None, None) => String::new(),
+    };
+    println!("last transmit:  {} {}", ago(&state.last_transmit), transmit);
+
+    println!("spooled:        {} reports", Spool::open()?.depth());
+    Ok(())
+}
+
+/// Print the agent's log, then keep printing what gets appended with `follow`
+pub async fn logs(follow: bool) -> Result<(), Error> {
+    let path = Agent::log_file()?;
+    if !path.exists() {
+        // the installed unit logs to the journal instead
+        #[cfg(target_os = "linux")]
+        if std::path::Path::new("/etc/systemd/system")
+            .join(Agent::service_name())
+            .exists()
+        {
+            return journal(follow);
+        }
+        return Err(Error::NoLog(path));
+    }
+    let mut position = 0;
+
+    loop {
+        if let Ok(mut file) = std::fs::File::open(&path) {
+            // start over if the log got truncated or replaced
+            if file.metadata()?.len() < position {
+                position = 0;
+            }
+            file.seek(SeekFrom::Start(position))?;
+
+            let mut appended = String::new();
+            position += file.read_to_string(&mut appended)? as u64;
+            print!("{}", appended);
+            std::io::stdout().flush()?;
+        }
+
+        if !follow {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Hand over to journalctl for the daemon's unit, it only returns if that fails
+#[cfg(target_os = "linux")]
+fn journal(follow: bool) -> Result<(), Error> {
+    use std::os::unix::process::CommandExt;
+
+    let mut journalctl = std::process::Command::new("journalctl");
+    journalctl.args(["-u", &Agent::service_name()]);
+    if follow {
+        journalctl.arg("-f");
+    }
+    Err(journalctl.exec().into())
+}
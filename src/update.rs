@@ -0,0 +1,287 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::transmit::{self, TransportConfig};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    IO(#[from] std::io::Error),
+    #[error("failed to download update: {}", transmit::describe(.0))]
+    Request(#[from] reqwest::Error),
+    #[error("{0}")]
+    Transport(#[from] transmit::Error),
+    #[error("release {0} has no build for {1}")]
+    NoArtifact(String, String),
+    #[error("this build has no release key to verify updates with")]
+    NoReleaseKey,
+    #[error("update doesn't match the release's sha-256 digest")]
+    Digest,
+    #[error("update isn't signed with the release key")]
+    Signature,
+    #[error("new binary is broken: {0}")]
+    Broken(String),
+    #[error("new binary failed its health check, rolled back: {0}")]
+    RolledBack(String),
+    #[error("Root access needed to replace the agent binary")]
+    Root,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateConfig {
+    /// install new releases from the daily update timer
+    #[serde(default)]
+    pub auto: bool,
+    /// where releases are published
+    #[serde(default = "origin_default")]
+    pub origin: String,
+    #[serde(default = "channel_default")]
+    pub channel: String,
+}
+
+fn origin_default() -> String {
+    "https://releases.reverseping.net".to_string()
+}
+
+fn channel_default() -> String {
+    "stable".to_string()
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            origin: origin_default(),
+            channel: channel_default(),
+        }
+    }
+}
+
+/// `{origin}/{channel}/latest.json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub version: String,
+    /// keyed by `{arch}-{os}`, e.g. `x86_64-linux`
+    pub artifacts: HashMap<String, Artifact>,
+}
+
+/// A release binary, its detached ed25519 signature is at `{url}.sig`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Artifact {
+    pub url: String,
+    pub sha256: String,
+}
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// hex encoded ed25519 public key releases are signed with, release.yml fails the
+/// build without it, local builds can't self-update
+const RELEASE_KEY: Option<&str> = option_env!("REVERSEPING_RELEASE_KEY");
+/// how long the restarted service has to stay up before the update counts as good
+#[cfg(target_os = "linux")]
+const SETTLE: Duration = Duration::from_secs(15);
+
+/// Check for a newer release and, unless `check_only`, install it
+pub async fn run(
+    config: &UpdateConfig,
+    transport: &TransportConfig,
+    check_only: bool,
+) -> Result<(), Error> {
+    let client = transmit::client(
+        transport,
+        reqwest::Client::builder().timeout(Duration::from_secs(300)),
+    )?;
+
+    let url = format!("{}/{}/latest.json", config.origin, config.channel);
+    let release: Release = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if !newer(&release.version, VERSION) {
+        log::info!("reverseping {} is up to date", VERSION);
+        return Ok(());
+    }
+    log::info!(
+        "reverseping {} is available, running {}",
+        release.version,
+        VERSION
+    );
+    if check_only {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    sudo::escalate_if_needed().map_err(|_| Error::Root)?;
+
+    let target = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    let artifact = release
+        .artifacts
+        .get(&target)
+        .ok_or_else(|| Error::NoArtifact(release.version.clone(), target))?;
+
+    let download = |url: String| {
+        let client = client.clone();
+        async move {
+            let response = client.get(url).send().await?.error_for_status()?;
+            Ok::<_, Error>(response.bytes().await?.to_vec())
+        }
+    };
+    let binary = download(artifact.url.clone()).await?;
+    let signature = download(format!("{}.sig", artifact.url)).await?;
+
+    let key = RELEASE_KEY
+        .and_then(|key| hex::decode(key.trim()).ok())
+        .ok_or(Error::NoReleaseKey)?;
+    verify(&binary, &artifact.sha256, &signature, &key)?;
+
+    install(&binary, &release.version)?;
+    log::info!("updated to reverseping {}", release.version);
+    Ok(())
+}
+
+/// whether dotted version `a` is newer than `b`
+fn newer(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+    parse(a) > parse(b)
+}
+
+/// Check the download against the release digest and its detached signature,
+/// which can be raw or hex encoded
+fn verify(binary: &[u8], sha256: &str, signature: &[u8], key: &[u8]) -> Result<(), Error> {
+    if !hex::encode(Sha256::digest(binary)).eq_ignore_ascii_case(sha256.trim()) {
+        return Err(Error::Digest);
+    }
+
+    let signature = match std::str::from_utf8(signature).ok().map(str::trim) {
+        Some(text) if text.len() == 128 => hex::decode(text).map_err(|_| Error::Signature)?,
+        _ => signature.to_vec(),
+    };
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(binary, &signature)
+        .map_err(|_| Error::Signature)
+}
+
+/// Swap the running binary for the new one, keeping the old one to roll back to
+fn install(binary: &[u8], version: &str) -> Result<(), Error> {
+    let exe = std::env::current_exe()?;
+    let new = exe.with_extension("new");
+    let old = exe.with_extension("old");
+
+    std::fs::write(&new, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if let Err(err) = check(&new, version) {
+        let _ = std::fs::remove_file(&new);
+        return Err(err);
+    }
+
+    // rename is atomic, the service never sees a half written binary
+    std::fs::copy(&exe, &old)?;
+    std::fs::rename(&new, &exe)?;
+
+    #[cfg(target_os = "linux")]
+    if let Err(reason) = restart() {
+        std::fs::rename(&old, &exe)?;
+        let _ = restart();
+        return Err(Error::RolledBack(reason));
+    }
+    Ok(())
+}
+
+/// the new binary runs on this host and is the version the release says
+fn check(binary: &Path, version: &str) -> Result<(), Error> {
+    let output = std::process::Command::new(binary)
+        .arg("--version")
+        .output()
+        .map_err(|err| Error::Broken(err.to_string()))?;
+    let reported = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !reported.contains(version.trim_start_matches('v')) {
+        return Err(Error::Broken(format!(
+            "expected version {}, it reported {:?}",
+            version,
+            reported.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Restart the service if it's installed and make sure it stays up
+#[cfg(target_os = "linux")]
+fn restart() -> Result<(), String> {
+    use crate::agent::Agent;
+
+    match Agent::service_state() {
+        Ok((enabled, _)) if enabled.is_empty() || enabled == "not-found" => return Ok(()),
+        Err(_) => return Ok(()),
+        _ => {}
+    }
+
+    // with Type=notify this waits for READY=1
+    let restarted = std::process::Command::new("systemctl")
+        .args(["restart", &Agent::service_name()])
+        .status()
+        .map_err(|err| err.to_string())?;
+    if !restarted.success() {
+        return Err("the service didn't start".to_string());
+    }
+
+    std::thread::sleep(SETTLE);
+    match Agent::service_state() {
+        Ok((_, active)) if active == "active" => Ok(()),
+        Ok((_, active)) => Err(format!("the service is {} after restarting", active)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    #[test]
+    fn test_verify() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = pair.public_key().as_ref();
+
+        let binary = b"new agent binary";
+        let digest = hex::encode(Sha256::digest(binary));
+        let signature = pair.sign(binary);
+
+        assert!(verify(binary, &digest, signature.as_ref(), key).is_ok());
+        let hex_signature = hex::encode(signature.as_ref());
+        assert!(verify(binary, &digest, hex_signature.as_bytes(), key).is_ok());
+
+        assert!(matches!(
+            verify(b"tampered binary!", &digest, signature.as_ref(), key),
+            Err(Error::Digest)
+        ));
+        let tampered = hex::encode(Sha256::digest(b"tampered binary!"));
+        assert!(matches!(
+            verify(b"tampered binary!", &tampered, signature.as_ref(), key),
+            Err(Error::Signature)
+        ));
+
+        assert!(newer("0.2.0", "0.1.9"));
+        assert!(newer("v0.10.0", "0.9.0"));
+        assert!(!newer("0.1.0", "0.1.0"));
+    }
+}